@@ -5,6 +5,8 @@ use super::{stream::CuStream, Cuda};
 pub struct CuContext<'a> {
     pub(crate) lib: &'a Cuda,
     pub(crate) inner: ffi::cuda::CUcontext,
+    // the device whose primary context this is, released instead of destroyed
+    pub(crate) primary: Option<ffi::cuda::CUdevice>,
 }
 
 impl CuContext<'_> {
@@ -38,7 +40,11 @@ impl CuContext<'_> {
 impl Drop for CuContext<'_> {
     fn drop(&mut self) {
         unsafe {
-            if !self.lib.cuCtxDestroy_v2(self.inner).ok() {
+            if let Some(device) = self.primary {
+                if !self.lib.cuDevicePrimaryCtxRelease_v2(device).ok() {
+                    tracing::error!("Failed to release primary cuda context.");
+                }
+            } else if !self.lib.cuCtxDestroy_v2(self.inner).ok() {
                 tracing::error!("Failed to destroy cuda context.");
             }
         }
@@ -59,4 +65,17 @@ mod test {
         let dev = cuda.new_device(0).unwrap();
         let _ctx = cuda.new_context(dev, 0).unwrap();
     }
+
+    #[test]
+    #[traced_test]
+    fn primary_context() {
+        let cuda = Cuda::new().unwrap();
+        cuda.init(0).unwrap();
+        let dev = cuda.new_device(0).unwrap();
+        let first = cuda.primary_context(dev).unwrap();
+        let second = cuda.primary_context(dev).unwrap();
+        assert_eq!(first.inner, second.inner);
+        drop(first);
+        assert!(second.get_api_version().is_ok());
+    }
 }
@@ -64,6 +64,7 @@ impl Cuda {
         let mut ctx = CuContext {
             lib: &self,
             inner: std::ptr::null_mut(),
+            primary: None,
         };
         let res = unsafe { (*self).cuCtxCreate_v2(&mut ctx.inner, flags, dev.inner) };
         assert!(!ctx.inner.is_null());
@@ -71,6 +72,21 @@ impl Cuda {
         res.result(ctx)
     }
 
+    /// Retains the primary context of a device, shared with every other user of the device.
+    ///
+    /// Unlike `new_context` the context is not made current, it lives until its last handle
+    /// is dropped.
+    pub fn primary_context(&self, dev: CuDevice) -> Result<CuContext<'_>, CUresult> {
+        let mut inner = std::ptr::null_mut();
+        unsafe { (*self).cuDevicePrimaryCtxRetain(&mut inner, dev.inner) }.err()?;
+
+        Ok(CuContext {
+            lib: self,
+            inner,
+            primary: Some(dev.inner),
+        })
+    }
+
     /// Returns a handle to a compute device.
     pub fn new_device(&self, ordinal: i32) -> Result<CuDevice, CUresult> {
         let mut d = CuDevice { inner: 0, lib: &self };
@@ -96,6 +112,21 @@ impl Deref for Cuda {
 }
 
 #[cfg(test)]
-mod test {
-    
+pub(crate) mod test {
+    use super::{CuContext, Cuda};
+
+    /// Loads and initializes CUDA, as every test on the GPU starts with.
+    pub(crate) fn cuda() -> Cuda {
+        let cuda = Cuda::new().unwrap();
+        cuda.init(0).unwrap();
+
+        cuda
+    }
+
+    /// A context of its own on the first device.
+    pub(crate) fn context(cuda: &Cuda) -> CuContext<'_> {
+        let device = cuda.new_device(0).unwrap();
+
+        cuda.new_context(device, 0).unwrap()
+    }
 }
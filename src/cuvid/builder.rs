@@ -0,0 +1,332 @@
+use crate::cuda::context::CuContext;
+use crate::cuda::device::CuDevice;
+
+use super::{
+    Codec, Cuvid, Decoder, DecoderError, FieldOutput, Rect, ScaleMode, VideoCreateFlags,
+    VideoDeinterlaceMode, VideoSurfaceFormat,
+};
+
+/// Most surfaces the driver maps at the same time.
+pub(crate) const MAX_OUTPUT_SURFACES: u32 = 64;
+
+/// Decoder options applied when the parser reports the sequence header.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DecoderSettings {
+    pub(crate) codec: Codec,
+    pub(crate) keyframe_only: bool,
+    pub(crate) low_latency: bool,
+    pub(crate) output_size: (u32, u32),
+    // `None` picks the format from the stream's chroma format and bit depth
+    pub(crate) output_format: Option<VideoSurfaceFormat>,
+    pub(crate) decode_surfaces: Option<u32>,
+    pub(crate) output_surfaces: u32,
+    // `None` weaves progressive sequences and adaptively deinterlaces the others
+    pub(crate) deinterlace_mode: Option<VideoDeinterlaceMode>,
+    pub(crate) field_output: FieldOutput,
+    pub(crate) create_flags: VideoCreateFlags,
+    pub(crate) max_size: (u32, u32),
+    pub(crate) drop_corrupted: bool,
+    pub(crate) operating_point: u32,
+    pub(crate) output_all_layers: bool,
+    pub(crate) crop: Option<Rect>,
+    pub(crate) scale_mode: ScaleMode,
+    pub(crate) target_rect: Option<Rect>,
+    pub(crate) clock_rate: u32,
+    pub(crate) histogram: bool,
+}
+
+/// Configures a `Decoder`, obtained from `Cuvid::decoder_builder`.
+///
+/// The defaults match `Cuvid::decoder`: NV12 output, 3 output surfaces, at least 12 decode
+/// surfaces and adaptive deinterlacing of interlaced streams. MJPEG decoders default to low
+/// latency and `auto_output_format` instead.
+pub struct DecoderBuilder<'a> {
+    nvcuvid: &'a Cuvid<'a>,
+    device: Option<CuDevice<'a>>,
+    context: Option<CuContext<'a>>,
+    settings: DecoderSettings,
+}
+
+impl<'a> DecoderBuilder<'a> {
+    pub(crate) fn new(nvcuvid: &'a Cuvid<'a>, codec: Codec) -> Self {
+        // MJPEG pictures are never reordered and come in any chroma format
+        let jpeg = codec == Codec::JPEG;
+        Self {
+            nvcuvid,
+            device: None,
+            context: None,
+            settings: DecoderSettings {
+                codec,
+                keyframe_only: false,
+                low_latency: jpeg,
+                output_size: (0, 0),
+                output_format: if jpeg {
+                    None
+                } else {
+                    Some(VideoSurfaceFormat::NV12)
+                },
+                decode_surfaces: None,
+                output_surfaces: 3,
+                deinterlace_mode: None,
+                field_output: FieldOutput::Frame,
+                create_flags: VideoCreateFlags::PreferCUVID,
+                max_size: (0, 0),
+                drop_corrupted: false,
+                operating_point: 0,
+                output_all_layers: false,
+                crop: None,
+                scale_mode: ScaleMode::Stretch,
+                target_rect: None,
+                clock_rate: 10_000_000,
+                histogram: false,
+            },
+        }
+    }
+
+    /// Decodes on a new context of `device`, the first GPU is used otherwise.
+    pub fn device(mut self, device: CuDevice<'a>) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Decodes on an existing context, takes precedence over `device`.
+    pub fn context(mut self, context: CuContext<'a>) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Only decodes intra pictures.
+    pub fn keyframe_only(mut self, keyframe_only: bool) -> Self {
+        self.settings.keyframe_only = keyframe_only;
+        self
+    }
+
+    /// Hands out pictures as soon as they are decoded instead of keeping one in the display queue.
+    pub fn low_latency(mut self, low_latency: bool) -> Self {
+        self.settings.low_latency = low_latency;
+        self
+    }
+
+    /// Scales the display area to `width`x`height`, `(0, 0)` keeps the stream's size.
+    pub fn output_size(mut self, width: u32, height: u32) -> Self {
+        self.settings.output_size = (width, height);
+        self
+    }
+
+    /// Decodes only `rect` of the coded picture instead of the stream's display area.
+    ///
+    /// Without an `output_size` the frames are the size of `rect`.
+    pub fn crop(mut self, rect: Rect) -> Self {
+        self.settings.crop = Some(rect);
+        self
+    }
+
+    /// How the cropped area is scaled to `output_size`, stretched by default.
+    pub fn scale_mode(mut self, mode: ScaleMode) -> Self {
+        self.settings.scale_mode = mode;
+        self
+    }
+
+    /// Places the scaled picture at `rect` within the output size, overriding `ScaleMode::Letterbox`.
+    pub fn target_rect(mut self, rect: Rect) -> Self {
+        self.settings.target_rect = Some(rect);
+        self
+    }
+
+    /// Decodes to `format`, creating the decoder fails if the GPU cannot output it.
+    pub fn output_format(mut self, format: VideoSurfaceFormat) -> Self {
+        self.settings.output_format = Some(format);
+        self
+    }
+
+    /// Picks the output format from the stream: NV12/P016 for 4:2:0 and YUV444/YUV444_16 for 4:4:4,
+    /// falling back to whatever the GPU supports.
+    pub fn auto_output_format(mut self) -> Self {
+        self.settings.output_format = None;
+        self
+    }
+
+    /// Number of surfaces the decoder decodes into, raised to the minimum the stream needs.
+    pub fn decode_surfaces(mut self, count: u32) -> Self {
+        self.settings.decode_surfaces = Some(count);
+        self
+    }
+
+    /// Number of surfaces that can be mapped at the same time, from 1 to 64.
+    pub fn output_surfaces(mut self, count: u32) -> Self {
+        self.settings.output_surfaces = count;
+        self
+    }
+
+    /// Deinterlacing applied to interlaced streams, progressive streams are always woven.
+    pub fn deinterlace_mode(mut self, mode: VideoDeinterlaceMode) -> Self {
+        self.settings.deinterlace_mode = Some(mode);
+        self
+    }
+
+    /// Whether interlaced pictures are output as one frame, one frame per field or as separate
+    /// fields, one frame by default.
+    pub fn field_output(mut self, field_output: FieldOutput) -> Self {
+        self.settings.field_output = field_output;
+        self
+    }
+
+    pub fn create_flags(mut self, flags: VideoCreateFlags) -> Self {
+        self.settings.create_flags = flags;
+        self
+    }
+
+    /// Largest coded size the decoder is allocated for, the stream's coded size is used if larger.
+    ///
+    /// Sequences up to this size reconfigure the decoder in place instead of recreating it.
+    pub fn max_size(mut self, width: u32, height: u32) -> Self {
+        self.settings.max_size = (width, height);
+        self
+    }
+
+    /// Skips frames decoded with errors instead of returning them with an error `DecodeStatus`.
+    ///
+    /// `Decoder::corrupted_frames` still counts them.
+    pub fn drop_corrupted(mut self, drop_corrupted: bool) -> Self {
+        self.settings.drop_corrupted = drop_corrupted;
+        self
+    }
+
+    /// AV1 operating point to decode, operating points the stream does not have fall back to 0.
+    pub fn operating_point(mut self, operating_point: u32) -> Self {
+        self.settings.operating_point = operating_point;
+        self
+    }
+
+    /// Outputs the frames of every spatial layer of the AV1 operating point instead of only the
+    /// highest one.
+    pub fn output_all_layers(mut self, output_all_layers: bool) -> Self {
+        self.settings.output_all_layers = output_all_layers;
+        self
+    }
+
+    /// Units per second of the queued timestamps, e.g. `90_000` for MPEG-TS or `1000` for WebM,
+    /// 10 MHz by default.
+    pub fn clock_rate(mut self, clock_rate: u32) -> Self {
+        self.settings.clock_rate = clock_rate;
+        self
+    }
+
+    /// Computes a luma histogram of every frame while decoding, see `GpuFrame::histogram`.
+    ///
+    /// Decoding fails with `DecoderError::HistogramUnsupported` if the GPU cannot for the stream.
+    pub fn histogram(mut self, histogram: bool) -> Self {
+        self.settings.histogram = histogram;
+        self
+    }
+
+    pub fn build(self) -> Result<Decoder<'a>, DecoderError> {
+        let output_surfaces = self.settings.output_surfaces;
+        if output_surfaces == 0 || output_surfaces > MAX_OUTPUT_SURFACES {
+            return Err(DecoderError::InvalidOutputSurfaces(output_surfaces));
+        }
+
+        Decoder::new(self.nvcuvid, self.settings, self.device, self.context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tracing_test::traced_test;
+
+    use super::super::test::{decode_packets, h264_stream};
+    use super::super::{
+        Codec, Cuvid, DecodeEvent, DecoderError, VideoCreateFlags, VideoDeinterlaceMode,
+        VideoSurfaceFormat,
+    };
+
+    #[test]
+    #[traced_test]
+    fn decoder_builder() {
+        let (cuda, packets) = h264_stream(256, 144, 2);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .auto_output_format()
+            .decode_surfaces(8)
+            .output_surfaces(2)
+            .deinterlace_mode(VideoDeinterlaceMode::Bob)
+            .create_flags(VideoCreateFlags::PreferCUVID)
+            .max_size(512, 288)
+            .build()
+            .unwrap();
+
+        let timestamps = decode_packets(&decoder, &packets, |frame| frame.timestamp);
+        assert_eq!(timestamps, vec![0, 1]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_output_surfaces() {
+        let (cuda, packets) = h264_stream(256, 144, 3);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        for count in [0, 65] {
+            assert_eq!(
+                cuvid
+                    .decoder_builder(Codec::H264)
+                    .output_surfaces(count)
+                    .build()
+                    .err(),
+                Some(DecoderError::InvalidOutputSurfaces(count))
+            );
+        }
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .output_surfaces(1)
+            .build()
+            .unwrap();
+        for (timestamp, packet) in packets.iter().enumerate() {
+            decoder.queue(packet, timestamp as i64).unwrap();
+        }
+        decoder.send_eos().unwrap();
+
+        let mut frames = decoder.frames(None);
+        let first = frames.next().unwrap().unwrap().into_frame().unwrap();
+        assert_eq!(
+            frames.next().unwrap().err(),
+            Some(DecoderError::AllSurfacesMapped(1))
+        );
+
+        let owned = first.to_owned_device_buffer().unwrap();
+        drop(first);
+        assert_eq!((owned.width, owned.height, owned.timestamp), (256, 144, 0));
+        assert_eq!(owned.format, VideoSurfaceFormat::NV12);
+
+        let timestamps = frames
+            .map(Result::unwrap)
+            .filter_map(DecodeEvent::into_frame)
+            .map(|frame| frame.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![1, 2]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_clock_rate() {
+        let (cuda, packets) = h264_stream(256, 144, 3);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .clock_rate(90_000)
+            .build()
+            .unwrap();
+        // 30 fps in a 90 kHz timebase
+        for (index, packet) in packets.iter().enumerate() {
+            decoder.queue(packet, 3000 * index as i64).unwrap();
+        }
+        decoder.send_eos().unwrap();
+
+        let timestamps = decoder
+            .frames(None)
+            .map(Result::unwrap)
+            .filter_map(DecodeEvent::into_frame)
+            .map(|frame| frame.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![0, 3000, 6000]);
+    }
+}
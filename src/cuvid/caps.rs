@@ -0,0 +1,66 @@
+use super::ffi;
+use super::VideoSurfaceFormat;
+
+/// What a GPU can decode for a codec, chroma format and bit depth, from `cuvidGetDecoderCaps`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecoderCaps {
+    pub supported: bool,
+    /// Number of NVDEC engines on the GPU.
+    pub decoder_count: u8,
+    pub max_size: (u32, u32),
+    pub min_size: (u32, u32),
+    /// Largest picture in 16x16 macroblocks.
+    pub max_macroblocks: u32,
+    /// Bit `n` is set if `VideoSurfaceFormat` `n` can be output.
+    pub output_format_mask: u16,
+    pub histogram_supported: bool,
+    /// Width of each histogram bin counter, in bits.
+    pub histogram_counter_bit_depth: u8,
+    pub histogram_max_bins: u16,
+}
+
+impl DecoderCaps {
+    pub fn supports_output_format(&self, format: VideoSurfaceFormat) -> bool {
+        self.output_format_mask & (1 << (format as u16)) != 0
+    }
+
+    /// Output formats the decoder can produce, in `VideoSurfaceFormat` order.
+    pub fn output_formats(&self) -> Vec<VideoSurfaceFormat> {
+        [
+            VideoSurfaceFormat::NV12,
+            VideoSurfaceFormat::P016,
+            VideoSurfaceFormat::YUV444,
+            VideoSurfaceFormat::YUV444_16,
+        ]
+        .iter()
+        .cloned()
+        .filter(|&format| self.supports_output_format(format))
+        .collect()
+    }
+
+    /// Whether a `width`x`height` coded picture is within the size and macroblock limits.
+    pub fn supports_size(&self, width: u32, height: u32) -> bool {
+        self.supported
+            && width >= self.min_size.0
+            && height >= self.min_size.1
+            && width <= self.max_size.0
+            && height <= self.max_size.1
+            && (width >> 4) * (height >> 4) <= self.max_macroblocks
+    }
+}
+
+impl From<ffi::cuvid::CUVIDDECODECAPS> for DecoderCaps {
+    fn from(caps: ffi::cuvid::CUVIDDECODECAPS) -> Self {
+        Self {
+            supported: caps.bIsSupported != 0,
+            decoder_count: caps.nNumNVDECs,
+            max_size: (caps.nMaxWidth, caps.nMaxHeight),
+            min_size: (caps.nMinWidth as _, caps.nMinHeight as _),
+            max_macroblocks: caps.nMaxMBCount,
+            output_format_mask: caps.nOutputFormatMask,
+            histogram_supported: caps.bIsHistogramSupported != 0,
+            histogram_counter_bit_depth: caps.nCounterBitDepth,
+            histogram_max_bins: caps.nMaxHistogramBins,
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use std::convert::TryFrom;
+
 use super::ffi;
+use super::DecoderError;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u32)]
@@ -15,22 +18,24 @@ impl Into<ffi::cuvid::cudaVideoChromaFormat> for VideoChromaFormat {
     }
 }
 
-impl From<ffi::cuvid::cudaVideoChromaFormat> for VideoChromaFormat {
-    fn from(format: ffi::cuvid::cudaVideoChromaFormat) -> Self {
+impl TryFrom<ffi::cuvid::cudaVideoChromaFormat> for VideoChromaFormat {
+    type Error = DecoderError;
+
+    fn try_from(format: ffi::cuvid::cudaVideoChromaFormat) -> Result<Self, Self::Error> {
         match format {
             ffi::cuvid::cudaVideoChromaFormat_enum_cudaVideoChromaFormat_Monochrome => {
-                VideoChromaFormat::Monochrome
+                Ok(VideoChromaFormat::Monochrome)
             }
             ffi::cuvid::cudaVideoChromaFormat_enum_cudaVideoChromaFormat_420 => {
-                VideoChromaFormat::YUV420
+                Ok(VideoChromaFormat::YUV420)
             }
             ffi::cuvid::cudaVideoChromaFormat_enum_cudaVideoChromaFormat_422 => {
-                VideoChromaFormat::YUV422
+                Ok(VideoChromaFormat::YUV422)
             }
             ffi::cuvid::cudaVideoChromaFormat_enum_cudaVideoChromaFormat_444 => {
-                VideoChromaFormat::YUV444
+                Ok(VideoChromaFormat::YUV444)
             }
-            _ => panic!("Invalid cuda video chrome format"),
+            _ => Err(DecoderError::UnknownChromaFormat(format)),
         }
     }
 }
@@ -1,4 +1,7 @@
+use std::convert::TryFrom;
+
 use super::ffi;
+use super::DecoderError;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u32)]
@@ -28,27 +31,29 @@ impl Into<ffi::cuvid::cudaVideoCodec> for Codec {
     }
 }
 
-impl From<ffi::cuvid::cudaVideoCodec> for Codec {
-    fn from(codec: ffi::cuvid::cudaVideoCodec) -> Self {
+impl TryFrom<ffi::cuvid::cudaVideoCodec> for Codec {
+    type Error = DecoderError;
+
+    fn try_from(codec: ffi::cuvid::cudaVideoCodec) -> Result<Self, Self::Error> {
         match codec {
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_MPEG1 => Codec::MPEG1,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_MPEG2 => Codec::MPEG2,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_VC1 => Codec::VC1,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264 => Codec::H264,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_JPEG => Codec::JPEG,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264_SVC => Codec::H264Svc,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264_MVC => Codec::H264Mvc,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_HEVC => Codec::HEVC,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_VP8 => Codec::VP8,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_VP9 => Codec::VP9,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_AV1 => Codec::AV1,
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_MPEG1 => Ok(Codec::MPEG1),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_MPEG2 => Ok(Codec::MPEG2),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_VC1 => Ok(Codec::VC1),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264 => Ok(Codec::H264),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_JPEG => Ok(Codec::JPEG),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264_SVC => Ok(Codec::H264Svc),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264_MVC => Ok(Codec::H264Mvc),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_HEVC => Ok(Codec::HEVC),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_VP8 => Ok(Codec::VP8),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_VP9 => Ok(Codec::VP9),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_AV1 => Ok(Codec::AV1),
 
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_YUV420 => Codec::YUV420,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_YV12 => Codec::YV12,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_NV12 => Codec::NV12,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_YUYV => Codec::YUYV,
-            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_UYVY => Codec::UYVY,
-            _ => panic!("Invalid cuda video codec"),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_YUV420 => Ok(Codec::YUV420),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_YV12 => Ok(Codec::YV12),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_NV12 => Ok(Codec::NV12),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_YUYV => Ok(Codec::YUYV),
+            ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_UYVY => Ok(Codec::UYVY),
+            _ => Err(DecoderError::UnknownCodec(codec)),
         }
     }
 }
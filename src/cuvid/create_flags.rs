@@ -0,0 +1,17 @@
+use super::ffi;
+
+/// Which engine the driver should prefer for decoding.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum VideoCreateFlags {
+    Default = ffi::cuvid::cudaVideoCreateFlags_enum_cudaVideoCreate_Default,
+    PreferCUDA = ffi::cuvid::cudaVideoCreateFlags_enum_cudaVideoCreate_PreferCUDA,
+    PreferDXVA = ffi::cuvid::cudaVideoCreateFlags_enum_cudaVideoCreate_PreferDXVA,
+    PreferCUVID = ffi::cuvid::cudaVideoCreateFlags_enum_cudaVideoCreate_PreferCUVID,
+}
+
+impl From<VideoCreateFlags> for ffi::cuvid::cudaVideoCreateFlags {
+    fn from(flags: VideoCreateFlags) -> Self {
+        flags as ffi::cuvid::cudaVideoCreateFlags
+    }
+}
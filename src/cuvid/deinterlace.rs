@@ -0,0 +1,153 @@
+use super::ffi;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum VideoDeinterlaceMode {
+    Weave = ffi::cuvid::cudaVideoDeinterlaceMode_enum_cudaVideoDeinterlaceMode_Weave,
+    Bob = ffi::cuvid::cudaVideoDeinterlaceMode_enum_cudaVideoDeinterlaceMode_Bob,
+    Adaptive = ffi::cuvid::cudaVideoDeinterlaceMode_enum_cudaVideoDeinterlaceMode_Adaptive,
+}
+
+impl From<VideoDeinterlaceMode> for ffi::cuvid::cudaVideoDeinterlaceMode {
+    fn from(mode: VideoDeinterlaceMode) -> Self {
+        mode as ffi::cuvid::cudaVideoDeinterlaceMode
+    }
+}
+
+/// How the pictures of interlaced streams are output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldOutput {
+    /// One frame per picture, deinterlaced with the decoder's `VideoDeinterlaceMode`.
+    Frame,
+    /// One deinterlaced frame per field, at twice the frame rate.
+    ///
+    /// `VideoDeinterlaceMode::Weave` falls back to `Adaptive`, progressive frames are output once.
+    /// The second frame is timestamped half a frame duration after the first, or with the same
+    /// timestamp if the stream has no frame rate.
+    DoubleRate,
+    /// Each field of the woven picture as a half-height frame, without deinterlacing.
+    SeparateFields,
+}
+
+/// The field of an interlaced picture a `GpuFrame` was made from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Field {
+    Top,
+    Bottom,
+}
+
+impl FieldOutput {
+    /// The deinterlacing the decoder is created with for a sequence.
+    pub(crate) fn deinterlace_mode(
+        self,
+        progressive_sequence: bool,
+        mode: Option<VideoDeinterlaceMode>,
+    ) -> VideoDeinterlaceMode {
+        match (self, mode) {
+            _ if progressive_sequence => VideoDeinterlaceMode::Weave,
+            (FieldOutput::SeparateFields, _) => VideoDeinterlaceMode::Weave,
+            (FieldOutput::DoubleRate, Some(VideoDeinterlaceMode::Weave)) | (_, None) => {
+                VideoDeinterlaceMode::Adaptive
+            }
+            (_, Some(mode)) => mode,
+        }
+    }
+
+    /// The frames to output for a displayed picture, as the `second_field` to map each with
+    /// and the field it shows.
+    ///
+    /// `repeat_first_field` is the parser's: the number of extra fields for pulled down
+    /// pictures, `-1` for a picture with a single field.
+    pub(crate) fn fields(
+        self,
+        progressive_frame: bool,
+        top_field_first: bool,
+        repeat_first_field: i32,
+    ) -> Vec<(i32, Option<Field>)> {
+        if progressive_frame || self == FieldOutput::Frame {
+            return vec![(0, None)];
+        }
+        let (first, second) = if top_field_first {
+            (Field::Top, Field::Bottom)
+        } else {
+            (Field::Bottom, Field::Top)
+        };
+        let count = if repeat_first_field < 0 {
+            1
+        } else {
+            2 + repeat_first_field
+        };
+
+        // a repeated field is the first one again
+        (0..count)
+            .map(|index| {
+                let field = if index % 2 == 0 { first } else { second };
+                let second_field = match self {
+                    FieldOutput::DoubleRate => index % 2,
+                    _ => 0,
+                };
+                (second_field, Some(field))
+            })
+            .collect()
+    }
+}
+
+/// Half of a frame duration at `frame_rate`, in `clock_rate` units, 0 without a frame rate.
+pub(crate) fn field_duration(clock_rate: u32, frame_rate: (u32, u32)) -> i64 {
+    let (numerator, denominator) = frame_rate;
+    if numerator == 0 {
+        return 0;
+    }
+
+    clock_rate as i64 * denominator as i64 / (2 * numerator as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{field_duration, Field, FieldOutput, VideoDeinterlaceMode};
+
+    #[test]
+    fn output_fields() {
+        assert_eq!(
+            FieldOutput::DoubleRate.fields(true, true, 0),
+            vec![(0, None)]
+        );
+        assert_eq!(FieldOutput::Frame.fields(false, true, 1), vec![(0, None)]);
+        assert_eq!(
+            FieldOutput::DoubleRate.fields(false, false, 1),
+            vec![
+                (0, Some(Field::Bottom)),
+                (1, Some(Field::Top)),
+                (0, Some(Field::Bottom)),
+            ]
+        );
+        assert_eq!(
+            FieldOutput::SeparateFields.fields(false, true, 0),
+            vec![(0, Some(Field::Top)), (0, Some(Field::Bottom))]
+        );
+        assert_eq!(
+            FieldOutput::SeparateFields.fields(false, true, -1),
+            vec![(0, Some(Field::Top))]
+        );
+
+        assert_eq!(
+            FieldOutput::DoubleRate.deinterlace_mode(false, Some(VideoDeinterlaceMode::Weave)),
+            VideoDeinterlaceMode::Adaptive
+        );
+        assert_eq!(
+            FieldOutput::SeparateFields.deinterlace_mode(false, Some(VideoDeinterlaceMode::Bob)),
+            VideoDeinterlaceMode::Weave
+        );
+        assert_eq!(
+            FieldOutput::Frame.deinterlace_mode(true, Some(VideoDeinterlaceMode::Bob)),
+            VideoDeinterlaceMode::Weave
+        );
+    }
+
+    #[test]
+    fn field_durations() {
+        assert_eq!(field_duration(10_000_000, (25, 1)), 200_000);
+        assert_eq!(field_duration(90_000, (30000, 1001)), 1501);
+        assert_eq!(field_duration(90_000, (0, 0)), 0);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::cuda::{Cuda, CudaResult};
+
+use super::{ffi, CUdeviceptr, VideoSurfaceFormat};
+
+/// A decoded frame copied out of the decoder surface into device memory it owns.
+///
+/// The planes are stacked like in the surface: luma rows first, then the chroma rows.
+pub struct DeviceFrame<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub ptr: CUdeviceptr,
+    pub pitch: u32,
+    pub timestamp: i64,
+    pub format: VideoSurfaceFormat,
+    pub(crate) cuda: &'a Cuda,
+    // the context the memory was allocated in
+    pub(crate) context: ffi::cuda::CUcontext,
+}
+
+impl Drop for DeviceFrame<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.cuda.cuCtxPushCurrent_v2(self.context).ok() {
+                tracing::error!("Failed to push current context.");
+            }
+
+            if !self.cuda.cuMemFree_v2(self.ptr as _).ok() {
+                tracing::error!("Failed to free frame memory.");
+            }
+
+            if !self.cuda.cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
+                tracing::error!("Failed to pop current context.");
+            }
+        }
+    }
+}
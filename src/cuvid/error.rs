@@ -0,0 +1,136 @@
+use std::fmt;
+
+use ffi::cuvid::CUresult;
+
+use super::{Codec, VideoChromaFormat, VideoSurfaceFormat, MAX_OUTPUT_SURFACES};
+
+/// Errors raised by the decoder, including the ones hit inside the parser callbacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecoderError {
+    /// A CUDA or NVDEC call failed.
+    Cuda(CUresult),
+    /// The driver reported a codec this crate does not know.
+    UnknownCodec(u32),
+    UnknownChromaFormat(u32),
+    UnknownSurfaceFormat(u32),
+    /// The GPU cannot decode this combination of codec, chroma format and bit depth.
+    Unsupported {
+        codec: Codec,
+        chroma_format: VideoChromaFormat,
+        bit_depth_minus8: u8,
+    },
+    /// The coded size exceeds what the GPU can decode.
+    SizeTooLarge {
+        size: (u32, u32),
+        max_size: (u32, u32),
+    },
+    /// The picture has more macroblocks than the GPU can decode.
+    TooManyMacroblocks {
+        count: u32,
+        max_count: u32,
+    },
+    /// The requested output format is not supported, `None` when no format is.
+    UnsupportedOutputFormat(Option<VideoSurfaceFormat>),
+    /// A histogram was requested but the GPU cannot compute one for the codec.
+    HistogramUnsupported(Codec),
+    /// The parser submitted a picture before any sequence header.
+    NotInitialized,
+    /// The output surface count is 0 or more than the driver allows.
+    InvalidOutputSurfaces(u32),
+    /// A picture index outside the `count` decode surfaces of the decoder.
+    InvalidSurfaceIndex {
+        index: i32,
+        count: u32,
+    },
+    /// Every output surface is mapped by a live `GpuFrame`, drop one before asking for the next.
+    AllSurfacesMapped(u32),
+    /// The rows of the destination are too short for the frame.
+    PitchTooSmall {
+        pitch: usize,
+        min_pitch: usize,
+    },
+    /// The destination cannot hold the frame at the given pitch.
+    BufferTooSmall {
+        size: usize,
+        required: usize,
+    },
+    /// The `queue_async` worker stopped before parsing the packet.
+    WorkerStopped,
+    /// A parser callback panicked, the panic message is kept.
+    Panic(String),
+}
+
+impl fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecoderError::Cuda(res) => write!(f, "CUDA error {}", res),
+            DecoderError::UnknownCodec(value) => write!(f, "unknown codec {}", value),
+            DecoderError::UnknownChromaFormat(value) => {
+                write!(f, "unknown chroma format {}", value)
+            }
+            DecoderError::UnknownSurfaceFormat(value) => {
+                write!(f, "unknown surface format {}", value)
+            }
+            DecoderError::Unsupported {
+                codec,
+                chroma_format,
+                bit_depth_minus8,
+            } => write!(
+                f,
+                "{:?} {:?} at {} bits is not supported on this GPU",
+                codec,
+                chroma_format,
+                bit_depth_minus8 + 8
+            ),
+            DecoderError::SizeTooLarge { size, max_size } => write!(
+                f,
+                "resolution {}x{} is greater than the maximum {}x{} for the GPU",
+                size.0, size.1, max_size.0, max_size.1
+            ),
+            DecoderError::TooManyMacroblocks { count, max_count } => write!(
+                f,
+                "{} macroblocks is more than the maximum {} for the GPU",
+                count, max_count
+            ),
+            DecoderError::UnsupportedOutputFormat(Some(format)) => {
+                write!(f, "output format {:?} is not supported", format)
+            }
+            DecoderError::UnsupportedOutputFormat(None) => {
+                write!(f, "no supported output format")
+            }
+            DecoderError::HistogramUnsupported(codec) => {
+                write!(f, "luma histograms are not supported for {:?}", codec)
+            }
+            DecoderError::NotInitialized => write!(f, "picture decoded before the sequence header"),
+            DecoderError::InvalidOutputSurfaces(count) => write!(
+                f,
+                "{} output surfaces requested, between 1 and {} are allowed",
+                count, MAX_OUTPUT_SURFACES
+            ),
+            DecoderError::InvalidSurfaceIndex { index, count } => write!(
+                f,
+                "picture index {} is outside the {} decode surfaces",
+                index, count
+            ),
+            DecoderError::AllSurfacesMapped(count) => {
+                write!(f, "all {} output surfaces are mapped", count)
+            }
+            DecoderError::PitchTooSmall { pitch, min_pitch } => {
+                write!(
+                    f,
+                    "pitch {} is less than the {} bytes of a row",
+                    pitch, min_pitch
+                )
+            }
+            DecoderError::BufferTooSmall { size, required } => write!(
+                f,
+                "buffer of {} bytes is too small for the {} bytes of the frame",
+                size, required
+            ),
+            DecoderError::WorkerStopped => write!(f, "parse worker stopped"),
+            DecoderError::Panic(ref message) => write!(f, "decoder callback panicked: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DecoderError {}
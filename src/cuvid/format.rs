@@ -0,0 +1,49 @@
+use super::ffi;
+use super::{Codec, Rect, VideoChromaFormat, VideoSurfaceFormat};
+
+/// Format of the decoded sequence and of the frames handed out for it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VideoFormat {
+    pub codec: Codec,
+    pub chroma_format: VideoChromaFormat,
+    pub bit_depth_minus8: u8,
+    /// Size of the decoded pictures, including padding.
+    pub coded_size: (u32, u32),
+    /// Part of the coded pictures meant to be displayed.
+    pub display_area: Rect,
+    /// Size of the frames returned by `FramesIter`.
+    pub output_size: (u32, u32),
+    pub output_format: VideoSurfaceFormat,
+    /// Frames per second as numerator and denominator, `(0, 0)` if the stream does not say.
+    pub frame_rate: (u32, u32),
+    pub progressive: bool,
+    /// Display aspect ratio as width and height, `(0, 0)` if the stream does not say.
+    pub display_aspect_ratio: (u32, u32),
+    /// Bits per second, 0 if the stream does not say.
+    pub bitrate: u32,
+    pub signal: VideoSignalDescription,
+}
+
+/// Colour description of the stream, with the values of ITU-T H.273.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VideoSignalDescription {
+    /// Original video format: 0 component, 1 PAL, 2 NTSC, 3 SECAM, 4 MAC, 5 unspecified.
+    pub video_format: u8,
+    pub full_range: bool,
+    pub color_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+}
+
+impl<'a> From<&'a ffi::cuvid::CUVIDEOFORMAT> for VideoSignalDescription {
+    fn from(fmt: &'a ffi::cuvid::CUVIDEOFORMAT) -> Self {
+        let signal = &fmt.video_signal_description;
+        Self {
+            video_format: signal.video_format(),
+            full_range: signal.video_full_range_flag() != 0,
+            color_primaries: signal.color_primaries,
+            transfer_characteristics: signal.transfer_characteristics,
+            matrix_coefficients: signal.matrix_coefficients,
+        }
+    }
+}
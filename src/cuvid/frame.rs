@@ -0,0 +1,34 @@
+use super::VideoSurfaceFormat;
+
+/// A decoded frame in host memory, from `GpuFrame::download`.
+///
+/// The planes are tightly packed and stacked: the luma rows, then the interleaved chroma rows
+/// for NV12 and P016, or the two chroma planes for YUV444.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub timestamp: i64,
+    pub format: VideoSurfaceFormat,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Bytes per row of every plane.
+    pub fn pitch(&self) -> usize {
+        (self.width * self.format.bytes_per_sample()) as usize
+    }
+
+    /// The planes of the frame, luma first.
+    pub fn planes(&self) -> Vec<&[u8]> {
+        let pitch = self.pitch();
+        self.format
+            .planes(self.height, self.height)
+            .into_iter()
+            .map(|(_, rows, row)| {
+                let start = row as usize * pitch;
+                &self.data[start..start + rows as usize * pitch]
+            })
+            .collect()
+    }
+}
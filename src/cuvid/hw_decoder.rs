@@ -0,0 +1,370 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::cuda::context::CuContext;
+
+use super::{
+    ffi, Codec, CudaResult, Cuvid, DecodeStatus, DecoderError, GpuFrame, VideoSurfaceFormat,
+    CUdeviceptr,
+};
+
+/// Drives NVDEC with pictures parsed by the caller, without the cuvid parser.
+///
+/// The caller fills `CUVIDPICPARAMS` from its own parser, picks the surface each picture is
+/// decoded into and maps the surfaces in display order.
+pub struct HwDecoder<'a> {
+    nvcuvid: &'a Cuvid<'a>,
+    context: CuContext<'a>,
+    lock: ffi::cuvid::CUvideoctxlock,
+    decoder: ffi::cuvid::CUvideodecoder,
+    codec: Codec,
+    format: VideoSurfaceFormat,
+    size: (u32, u32),
+    decode_surfaces: u32,
+    output_surfaces: u32,
+    mapped_frames: AtomicUsize,
+    // whether film grain is applied to the picture decoded in each surface
+    film_grain: Mutex<Vec<bool>>,
+}
+
+unsafe impl Send for HwDecoder<'_> {}
+unsafe impl Sync for HwDecoder<'_> {}
+
+impl<'a> HwDecoder<'a> {
+    /// Creates the decoder described by `info` on `context`.
+    ///
+    /// `vidLock` is replaced by a lock of the decoder's own, a zero target size decodes at the
+    /// coded size.
+    pub(crate) fn new(
+        nvcuvid: &'a Cuvid<'a>,
+        context: CuContext<'a>,
+        mut info: ffi::cuvid::CUVIDDECODECREATEINFO,
+    ) -> Result<Self, DecoderError> {
+        let codec = Codec::try_from(info.CodecType)?;
+        let format = VideoSurfaceFormat::try_from(info.OutputFormat)?;
+        if info.ulTargetWidth == 0 || info.ulTargetHeight == 0 {
+            info.ulTargetWidth = info.ulWidth;
+            info.ulTargetHeight = info.ulHeight;
+        }
+
+        let mut lock: ffi::cuvid::CUvideoctxlock = std::ptr::null_mut();
+        unsafe { nvcuvid.cuvidCtxLockCreate(&mut lock, context.inner as _) }
+            .err()
+            .map_err(DecoderError::Cuda)?;
+        info.vidLock = lock;
+
+        // from here on dropping the decoder releases the lock
+        let mut decoder = Self {
+            nvcuvid,
+            context,
+            lock,
+            decoder: std::ptr::null_mut(),
+            codec,
+            format,
+            size: (info.ulTargetWidth as _, info.ulTargetHeight as _),
+            decode_surfaces: info.ulNumDecodeSurfaces as _,
+            output_surfaces: info.ulNumOutputSurfaces as _,
+            mapped_frames: AtomicUsize::new(0),
+            film_grain: Mutex::new(vec![false; info.ulNumDecodeSurfaces as usize]),
+        };
+        let mut raw = std::ptr::null_mut();
+        decoder.in_context(|| unsafe { nvcuvid.cuvidCreateDecoder(&mut raw, &mut info) })?;
+        decoder.decoder = raw;
+
+        Ok(decoder)
+    }
+
+    /// Decodes a picture into surface `params.CurrPicIdx`.
+    ///
+    /// The call returns once the picture is submitted, mapping the surface waits for it.
+    /// Fails with `DecoderError::InvalidSurfaceIndex` if the decoder has no such surface.
+    pub fn decode(&self, params: &mut ffi::cuvid::CUVIDPICPARAMS) -> Result<(), DecoderError> {
+        let index = self.surface(params.CurrPicIdx)?;
+        {
+            let mut film_grain = self.film_grain.lock().unwrap_or_else(|e| e.into_inner());
+            film_grain[index] =
+                self.codec == Codec::AV1 && unsafe { params.CodecSpecific.av1.apply_grain() } != 0;
+        }
+
+        self.in_context(|| unsafe { self.nvcuvid.cuvidDecodePicture(self.decoder, params) })
+    }
+
+    /// Outcome of decoding the picture in surface `index`, `None` if there is no such surface or
+    /// the driver cannot tell.
+    pub fn status(&self, index: i32) -> Option<DecodeStatus> {
+        self.surface(index).ok()?;
+        let mut status: ffi::cuvid::CUVIDGETDECODESTATUS = unsafe { std::mem::zeroed() };
+        unsafe {
+            self.nvcuvid
+                .cuvidGetDecodeStatus(self.decoder, index, &mut status)
+        }
+        .result(status.decodeStatus)
+        .ok()
+        .and_then(DecodeStatus::from_raw)
+    }
+
+    /// Maps surface `index` for display, post-processed with `params`.
+    ///
+    /// Fails with `DecoderError::InvalidSurfaceIndex` if the decoder has no such surface and
+    /// with `DecoderError::AllSurfacesMapped` while every output surface is mapped.
+    pub fn map(
+        &self,
+        index: i32,
+        timestamp: i64,
+        params: &ffi::cuvid::CUVIDPROCPARAMS,
+    ) -> Result<GpuFrame<'_>, DecoderError> {
+        let surface = self.surface(index)?;
+        let limit = self.output_surfaces as usize;
+        self.mapped_frames
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |mapped| {
+                if mapped < limit {
+                    Some(mapped + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| DecoderError::AllSurfacesMapped(self.output_surfaces))?;
+
+        let mut params = *params;
+        let mut ptr: CUdeviceptr = 0;
+        let mut pitch = 0u32;
+        let res = self.in_context(|| unsafe {
+            self.nvcuvid.cuvidMapVideoFrame64(
+                self.decoder,
+                index,
+                &mut ptr,
+                &mut pitch,
+                &mut params,
+            )
+        });
+        if let Err(err) = res {
+            self.mapped_frames.fetch_sub(1, Ordering::AcqRel);
+            return Err(err);
+        }
+
+        let film_grain = self.film_grain.lock().unwrap_or_else(|e| e.into_inner())[surface];
+
+        Ok(GpuFrame {
+            nvcuvid: self.nvcuvid,
+            width: self.size.0,
+            height: self.size.1,
+            ptr,
+            pitch,
+            timestamp,
+            format: self.format,
+            status: self.status(index),
+            film_grain,
+            sei: Vec::new(),
+            field: None,
+            decoder: self.decoder,
+            _owner: None,
+            mapped_ptr: ptr,
+            histogram: None,
+            surface_height: self.size.1,
+            mapped_frames: &self.mapped_frames,
+            context: &self.context,
+        })
+    }
+
+    /// `index` as a position in the decode surfaces, if the decoder has that many.
+    fn surface(&self, index: i32) -> Result<usize, DecoderError> {
+        if index < 0 || index as u32 >= self.decode_surfaces {
+            return Err(DecoderError::InvalidSurfaceIndex {
+                index,
+                count: self.decode_surfaces,
+            });
+        }
+
+        Ok(index as usize)
+    }
+
+    /// Runs `f` with the decoder's context current.
+    fn in_context<F>(&self, f: F) -> Result<(), DecoderError>
+    where
+        F: FnOnce() -> ffi::cuvid::CUresult,
+    {
+        let cuda = self.nvcuvid.cuda;
+        unsafe { cuda.cuCtxPushCurrent_v2(self.context.inner) }
+            .err()
+            .map_err(DecoderError::Cuda)?;
+        let res = f();
+        unsafe { cuda.cuCtxPopCurrent_v2(std::ptr::null_mut()) }
+            .err()
+            .map_err(DecoderError::Cuda)?;
+
+        res.err().map_err(DecoderError::Cuda)
+    }
+}
+
+impl Drop for HwDecoder<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.decoder.is_null() {
+                if !self
+                    .nvcuvid
+                    .cuda
+                    .cuCtxPushCurrent_v2(self.context.inner)
+                    .ok()
+                {
+                    tracing::error!("Failed to push current context.");
+                }
+                if !self.nvcuvid.cuvidDestroyDecoder(self.decoder).ok() {
+                    tracing::error!("Failed to destroy decoder.");
+                }
+                if !self
+                    .nvcuvid
+                    .cuda
+                    .cuCtxPopCurrent_v2(std::ptr::null_mut())
+                    .ok()
+                {
+                    tracing::error!("Failed to pop current context.");
+                }
+            }
+            if !self.nvcuvid.cuvidCtxLockDestroy(self.lock).ok() {
+                tracing::error!("Failed to destroy context lock.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cuda::Cuda;
+    use tracing_test::traced_test;
+
+    use super::super::test::h264_stream;
+    use super::super::{ffi, Cuvid, DecodeStatus, DecoderError, Packet, VideoSurfaceFormat};
+    use super::HwDecoder;
+
+    /// Stands in for a caller's own parser, forwarding the pictures of the cuvid one.
+    struct Session<'a> {
+        cuda: &'a Cuda,
+        cuvid: &'a Cuvid<'a>,
+        decoder: Option<HwDecoder<'a>>,
+        displayed: Vec<(u32, u32, i64, Option<DecodeStatus>)>,
+    }
+
+    unsafe extern "C" fn sequence(
+        user_data: *mut std::os::raw::c_void,
+        format: *mut ffi::cuvid::CUVIDEOFORMAT,
+    ) -> i32 {
+        let session = &mut *(user_data as *mut Session);
+        let format = &*format;
+
+        let mut info: ffi::cuvid::CUVIDDECODECREATEINFO = std::mem::zeroed();
+        info.CodecType = format.codec;
+        info.ChromaFormat = format.chroma_format;
+        info.OutputFormat = VideoSurfaceFormat::NV12.into();
+        info.ulWidth = format.coded_width as _;
+        info.ulHeight = format.coded_height as _;
+        info.ulMaxWidth = format.coded_width as _;
+        info.ulMaxHeight = format.coded_height as _;
+        info.ulNumDecodeSurfaces = format.min_num_decode_surfaces as _;
+        info.ulNumOutputSurfaces = 1;
+
+        let context = match session.cuda.new_device(0) {
+            Ok(device) => session.cuda.new_context(device, 0),
+            Err(err) => Err(err),
+        };
+        match context.map(|context| session.cuvid.hw_decoder(context, info)) {
+            Ok(Ok(decoder)) => {
+                session.decoder = Some(decoder);
+                format.min_num_decode_surfaces as i32
+            }
+            _ => 0,
+        }
+    }
+
+    unsafe extern "C" fn decode(
+        user_data: *mut std::os::raw::c_void,
+        params: *mut ffi::cuvid::CUVIDPICPARAMS,
+    ) -> i32 {
+        let session = &mut *(user_data as *mut Session);
+        match session.decoder.as_ref() {
+            Some(decoder) => decoder.decode(&mut *params).is_ok() as i32,
+            None => 0,
+        }
+    }
+
+    unsafe extern "C" fn display(
+        user_data: *mut std::os::raw::c_void,
+        info: *mut ffi::cuvid::CUVIDPARSERDISPINFO,
+    ) -> i32 {
+        let session = &mut *(user_data as *mut Session);
+        if info.is_null() {
+            return 1;
+        }
+        let info = &*info;
+        let mut params: ffi::cuvid::CUVIDPROCPARAMS = std::mem::zeroed();
+        params.progressive_frame = info.progressive_frame;
+        params.top_field_first = info.top_field_first;
+
+        let displayed = match session.decoder.as_ref() {
+            Some(decoder) => match decoder.map(info.picture_index, info.timestamp, &params) {
+                Ok(frame) => (frame.width, frame.height, frame.timestamp, frame.status),
+                Err(_) => return 0,
+            },
+            None => return 0,
+        };
+        session.displayed.push(displayed);
+
+        1
+    }
+
+    #[test]
+    #[traced_test]
+    fn hw_decode_h264() {
+        let (cuda, packets) = h264_stream(256, 144, 3);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let mut session = Session {
+            cuda: &cuda,
+            cuvid: &cuvid,
+            decoder: None,
+            displayed: Vec::new(),
+        };
+
+        let mut params: ffi::cuvid::CUVIDPARSERPARAMS = unsafe { std::mem::zeroed() };
+        params.CodecType = ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264;
+        params.ulMaxNumDecodeSurfaces = 1;
+        params.pfnSequenceCallback = Some(sequence);
+        params.pfnDecodePicture = Some(decode);
+        params.pfnDisplayPicture = Some(display);
+        params.pUserData = (&mut session as *mut Session) as *mut std::os::raw::c_void;
+
+        let mut parser = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(cuvid.cuvidCreateVideoParser(&mut parser, &mut params), 0);
+            for (timestamp, packet) in packets.iter().enumerate() {
+                let mut packet = Packet::new(packet).timestamp(timestamp as i64).raw();
+                assert_eq!(cuvid.cuvidParseVideoData(parser, &mut packet), 0);
+            }
+            let mut packet = Packet::new(&[]).end_of_stream(true).raw();
+            assert_eq!(cuvid.cuvidParseVideoData(parser, &mut packet), 0);
+            cuvid.cuvidDestroyVideoParser(parser);
+        }
+
+        let decoder = session.decoder.as_ref().unwrap();
+        let count = decoder.decode_surfaces;
+        let mut params: ffi::cuvid::CUVIDPICPARAMS = unsafe { std::mem::zeroed() };
+        params.CurrPicIdx = count as i32;
+        assert_eq!(
+            decoder.decode(&mut params),
+            Err(DecoderError::InvalidSurfaceIndex {
+                index: count as i32,
+                count
+            })
+        );
+        let params: ffi::cuvid::CUVIDPROCPARAMS = unsafe { std::mem::zeroed() };
+        assert_eq!(
+            decoder.map(-1, 0, &params).err(),
+            Some(DecoderError::InvalidSurfaceIndex { index: -1, count })
+        );
+        assert_eq!(
+            session.displayed,
+            (0..3)
+                .map(|timestamp| (256, 144, timestamp, Some(DecodeStatus::Success)))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,8 +1,21 @@
+use std::convert::TryFrom;
+use std::future::Future;
+use std::fmt;
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+
+use futures_core::Stream;
 
 use crate::cuda::Cuda;
 use crate::cuda::context::CuContext;
 use crate::cuda::device::CuDevice;
+use crate::cuda::stream::CuStream;
 
 use super::ffi;
 
@@ -10,39 +23,129 @@ use super::cuda::CudaResult;
 pub use ffi::cuvid::CUdeviceptr;
 use ffi::cuvid::CUresult;
 
+mod builder;
+mod caps;
 mod chroma;
 mod codec;
+mod create_flags;
+mod deinterlace;
+mod device_frame;
+mod error;
+mod format;
+mod frame;
+mod hw_decoder;
+mod packet;
+mod scale;
+mod sei;
+mod status;
 mod surface;
 
+pub use self::builder::DecoderBuilder;
+use self::builder::{DecoderSettings, MAX_OUTPUT_SURFACES};
+pub use self::caps::DecoderCaps;
 pub use self::chroma::VideoChromaFormat;
 pub use self::codec::Codec;
+pub use self::create_flags::VideoCreateFlags;
+pub use self::deinterlace::{Field, FieldOutput, VideoDeinterlaceMode};
+pub use self::device_frame::DeviceFrame;
+pub use self::error::DecoderError;
+pub use self::format::{VideoFormat, VideoSignalDescription};
+pub use self::frame::Frame;
+pub use self::hw_decoder::HwDecoder;
+pub use self::packet::Packet;
+use self::scale::Geometry;
+pub use self::scale::{Rect, ScaleMode};
+pub use self::sei::{ContentLightLevel, MasteringDisplay, SeiMessage, TimeCode};
+pub use self::status::DecodeStatus;
 pub use self::surface::VideoSurfaceFormat;
 
-struct Cuvid<'a> {
-    lib: ffi::cuvid::nvcuvid,
-    cuda: &'a Cuda
+pub struct Cuvid<'a> {
+    lib: Arc<ffi::cuvid::nvcuvid>,
+    cuda: &'a Cuda,
 }
+
 impl<'a> Cuvid<'a> {
-    pub fn new(cuda: &Cuda) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(cuda: &'a Cuda) -> Result<Self, Box<dyn std::error::Error>> {
         let library_name = libloading::library_filename("nvcuvid");
         let lib = unsafe { ffi::cuvid::nvcuvid::new(library_name) }?;
+        let lib = Arc::new(lib);
         Ok(Self { lib, cuda })
     }
 
     pub fn init(&self, flags: u32) -> Result<(), CUresult> {
-        unsafe { (*self).cuInit(flags).err() }
+        self.cuda.init(flags)
     }
 
-    pub fn decoder(
-        &self,
+    /// Creates a decoder on `context`, or on a new context of `device` (the first GPU by default).
+    pub fn decoder<'b>(
+        &'b self,
         codec: Codec,
         keyframe_only: bool,
         low_latency: bool,
         output_size: (u32, u32),
-        device: Option<CuDevice>,
-        context: Option<CuContext>
-    ) -> Result<Decoder<'a>, CUresult> {
-        Decoder::new(&self, codec, keyframe_only, low_latency, output_size, device, context)
+        device: Option<CuDevice<'b>>,
+        context: Option<CuContext<'b>>,
+    ) -> Result<Decoder<'b>, DecoderError> {
+        let mut builder = self
+            .decoder_builder(codec)
+            .keyframe_only(keyframe_only)
+            .low_latency(low_latency)
+            .output_size(output_size.0, output_size.1);
+        if let Some(device) = device {
+            builder = builder.device(device);
+        }
+        if let Some(context) = context {
+            builder = builder.context(context);
+        }
+
+        builder.build()
+    }
+
+    /// Queries what the GPU of `context` can decode for `codec`, `chroma_format` and bit depth.
+    pub fn decoder_caps(
+        &self,
+        context: &CuContext,
+        codec: Codec,
+        chroma_format: VideoChromaFormat,
+        bit_depth_minus8: u8,
+    ) -> Result<DecoderCaps, DecoderError> {
+        let mut caps: ffi::cuvid::CUVIDDECODECAPS = unsafe { std::mem::zeroed() };
+        caps.eCodecType = codec.into();
+        caps.eChromaFormat = chroma_format.into();
+        caps.nBitDepthMinus8 = bit_depth_minus8 as _;
+
+        unsafe {
+            self.cuda
+                .cuCtxPushCurrent_v2(context.inner)
+                .err()
+                .map_err(DecoderError::Cuda)?;
+            let res = self.lib.cuvidGetDecoderCaps(&mut caps);
+            self.cuda
+                .cuCtxPopCurrent_v2(std::ptr::null_mut())
+                .err()
+                .map_err(DecoderError::Cuda)?;
+            res.err().map_err(DecoderError::Cuda)?;
+        }
+
+        Ok(DecoderCaps::from(caps))
+    }
+
+    /// Creates a decoder fed with pictures parsed by the caller, see `HwDecoder`.
+    pub fn hw_decoder(
+        &'a self,
+        context: CuContext<'a>,
+        info: ffi::cuvid::CUVIDDECODECREATEINFO,
+    ) -> Result<HwDecoder<'a>, DecoderError> {
+        HwDecoder::new(self, context, info)
+    }
+
+    /// Starts configuring a decoder for `codec`.
+    ///
+    /// `Codec::JPEG` decodes MJPEG, one JPEG picture per queued packet. Such decoders default to
+    /// low latency and to the output format of the JPEG chroma format: 4:4:4 pictures keep their
+    /// chroma while 4:2:2 and greyscale ones are output as NV12.
+    pub fn decoder_builder(&self, codec: Codec) -> DecoderBuilder<'_> {
+        DecoderBuilder::new(self, codec)
     }
 }
 
@@ -54,23 +157,51 @@ impl Deref for Cuvid<'_> {
     }
 }
 
-
 pub struct Decoder<'a> {
     inner: Box<Inner<'a>>,
+    // thread parsing the packets of `queue_async`, started on first use
+    worker: Mutex<Option<ParseWorker>>,
 }
 
+// the parser callbacks run on a shared `Inner` and change it only through its mutexes
 unsafe impl Send for Decoder<'_> {}
 unsafe impl Sync for Decoder<'_> {}
 
 struct Inner<'a> {
-        nvcuvid: &'a Cuvid<'a>,
-        parser: ffi::cuvid::CUvideoparser,
+    nvcuvid: &'a Cuvid<'a>,
+    // the parser is not reentrant, `queue` and the `queue_async` worker take turns
+    parser: Mutex<ffi::cuvid::CUvideoparser>,
     lock: ffi::cuvid::CUvideoctxlock,
-    context: super::cuda::context::CuContext,
-    decoder: ffi::cuvid::CUvideodecoder,
-    keyframe_only: bool,
-    requested_size: (u32, u32),
+    context: CuContext<'a>,
+    settings: DecoderSettings,
+    corrupted_frames: AtomicU64,
+    // frames mapped by live `GpuFrame`s, at most `settings.output_surfaces`
+    mapped_frames: AtomicUsize,
+    // frames that could not be mapped yet as all output surfaces were taken
+    pending: Mutex<VecDeque<(Box<PreparedFrame>, Option<DecodeStatus>)>>,
+    // first error raised in a parser callback since the last `queue`
+    error: Mutex<Option<DecoderError>>,
+    // what the parser callbacks keep between calls, each of them locks it while it runs
+    state: Mutex<ParseState>,
+    // the format of the current sequence, shared with `Decoder::video_format`
+    format: Mutex<Option<VideoFormat>>,
+    sender: flume::Sender<Message>,
+    receiver: flume::Receiver<Message>,
+}
 
+/// The part of the decoder only the parser callbacks change.
+struct ParseState {
+    decoder: Option<Arc<SharedDecoder>>,
+    decode_surfaces: u64,
+    max_size: (u32, u32),
+    // rows of the decoder surfaces, the chroma planes start after them
+    surface_height: u32,
+    // whether film grain is applied to the picture decoded in each surface
+    film_grain: Vec<bool>,
+    // SEI messages of the picture decoded in each surface, until it is displayed
+    sei: Vec<Vec<SeiMessage>>,
+    // layout of the histograms the decoder computes, if enabled
+    histogram: Option<HistogramLayout>,
     video_fmt: Option<ffi::cuvid::CUVIDEOFORMAT>,
     codec: Codec,
     chroma_format: VideoChromaFormat,
@@ -79,8 +210,14 @@ struct Inner<'a> {
     output_format: VideoSurfaceFormat,
     out_size: (u32, u32),
     coded_size: (u32, u32),
-    sender: Option<flume::Sender<PreparedFrame>>,
-    receiver: flume::Receiver<PreparedFrame>,
+}
+
+enum Message {
+    Frame(Box<PreparedFrame>),
+    SequenceChanged(VideoFormat),
+    Error(DecoderError),
+    // every picture queued before the end of stream was displayed
+    EndOfStream,
 }
 
 #[derive(Debug)]
@@ -88,6 +225,60 @@ struct PreparedFrame {
     timestamp: i64,
     index: i32,
     parameters: ffi::cuvid::CUVIDPROCPARAMS,
+    // the decoder that decoded the picture and its output size at the time
+    decoder: Arc<SharedDecoder>,
+    size: (u32, u32),
+    surface_height: u32,
+    format: VideoSurfaceFormat,
+    film_grain: bool,
+    sei: Vec<SeiMessage>,
+    field: Option<Field>,
+    histogram: Option<HistogramLayout>,
+}
+
+/// A decoder shared by the frames decoded with it, destroyed with the last of them.
+///
+/// A decoder replaced on a sequence change lives on while its frames are queued or mapped.
+struct SharedDecoder {
+    nvcuvid: Arc<ffi::cuvid::nvcuvid>,
+    cuda: Arc<ffi::cuda::cuda>,
+    // the context of the `Decoder`, which drops every frame before destroying it
+    context: ffi::cuda::CUcontext,
+    inner: ffi::cuvid::CUvideodecoder,
+}
+
+// The handles are only passed to the driver, which takes them from any thread with the context
+// pushed, and the last frame holding the decoder destroys it wherever it is dropped.
+unsafe impl Send for SharedDecoder {}
+unsafe impl Sync for SharedDecoder {}
+
+impl fmt::Debug for SharedDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SharedDecoder").field(&self.inner).finish()
+    }
+}
+
+impl Drop for SharedDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.cuda.cuCtxPushCurrent_v2(self.context).ok() {
+                tracing::error!("Failed to push current context.");
+            }
+            if !self.nvcuvid.cuvidDestroyDecoder(self.inner).ok() {
+                tracing::error!("Failed to destroy decoder.");
+            }
+            if !self.cuda.cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
+                tracing::error!("Failed to pop current context.");
+            }
+        }
+    }
+}
+
+/// Bin count and counter bit depth of the histograms of a decoder.
+#[derive(Clone, Copy, Debug)]
+struct HistogramLayout {
+    bins: u16,
+    counter_bit_depth: u8,
 }
 
 impl PreparedFrame {
@@ -96,149 +287,668 @@ impl PreparedFrame {
     }
 }
 
+/// A decoded frame mapped from a decoder output surface, unmapped on drop.
+///
+/// The frame borrows the `Decoder` it came from so it cannot outlive it, and holds one of its
+/// output surfaces until dropped.
 pub struct GpuFrame<'a> {
     pub nvcuvid: &'a Cuvid<'a>,
     pub width: u32,
     pub height: u32,
     pub ptr: CUdeviceptr,
     pub pitch: u32,
+    /// Timestamp queued with the packet the picture started in, in units of the decoder's clock
+    /// rate.
+    ///
+    /// Frames come in presentation order, so queued presentation timestamps are increasing here
+    /// even with B-frames, see `FieldOutput::DoubleRate` for frames output per field.
     pub timestamp: i64,
+    pub format: VideoSurfaceFormat,
+    /// `None` if the driver cannot report the status for the codec.
+    pub status: Option<DecodeStatus>,
+    /// Whether AV1 film grain synthesis was applied to the frame.
+    pub film_grain: bool,
+    /// SEI messages, or AV1 metadata, that came with the picture, on the first frame output
+    /// for it.
+    pub sei: Vec<SeiMessage>,
+    /// The field the frame shows, `None` unless interlaced pictures are output per field.
+    pub field: Option<Field>,
     decoder: ffi::cuvid::CUvideodecoder,
+    // keeps a decoder replaced on a sequence change alive while the frame is mapped
+    _owner: Option<Arc<SharedDecoder>>,
+    // the pointer the surface was mapped at, `ptr` is one row down for bottom fields
+    mapped_ptr: CUdeviceptr,
+    // where the driver wrote the histogram, valid while mapped
+    histogram: Option<(CUdeviceptr, HistogramLayout)>,
+    surface_height: u32,
+    mapped_frames: &'a AtomicUsize,
+    // the context the frame was mapped in, which has to outlive it
+    context: &'a CuContext<'a>,
+}
+
+impl<'a> GpuFrame<'a> {
+    /// Copies the frame to new device memory, so the surface can be released by dropping this.
+    pub fn to_owned_device_buffer(&self) -> Result<DeviceFrame<'a>, DecoderError> {
+        let cuda = self.nvcuvid.cuda;
+        let mut ptr = 0;
+        let mut pitch = 0;
+
+        self.in_context(|| unsafe {
+            cuda.cuMemAllocPitch_v2(
+                &mut ptr,
+                &mut pitch,
+                self.row_bytes() as _,
+                self.format.rows(self.height) as _,
+                16,
+            )
+            .err()
+        })?;
+        // owns the memory from here, freeing it if the copy fails
+        let frame = DeviceFrame {
+            width: self.width,
+            height: self.height,
+            ptr: ptr as _,
+            pitch: pitch as _,
+            timestamp: self.timestamp,
+            format: self.format,
+            cuda,
+            context: self.context.inner,
+        };
+        self.in_context(|| {
+            self.copy_planes(None, |copy| {
+                copy.dstMemoryType = ffi::cuda::CUmemorytype_enum_CU_MEMORYTYPE_DEVICE;
+                copy.dstDevice = ptr;
+                copy.dstPitch = pitch;
+            })
+        })?;
+
+        Ok(frame)
+    }
+
+    /// Copies the frame to host memory with tightly packed planes.
+    pub fn download(&self) -> Result<Frame, DecoderError> {
+        let pitch = self.row_bytes();
+        let mut data = vec![0; pitch * self.format.rows(self.height) as usize];
+        self.download_into(&mut data, pitch, None)?;
+
+        Ok(Frame {
+            width: self.width,
+            height: self.height,
+            timestamp: self.timestamp,
+            format: self.format,
+            data,
+        })
+    }
+
+    /// Copies the frame to `dst`, `pitch` bytes per row with the planes stacked.
+    ///
+    /// The copy is queued on `stream` if given and waited for before returning. An empty frame
+    /// copies nothing.
+    pub fn download_into(
+        &self,
+        dst: &mut [u8],
+        pitch: usize,
+        stream: Option<&CuStream>,
+    ) -> Result<(), DecoderError> {
+        // nothing to copy, and no last row to size the destination by
+        if self.width == 0 || self.height == 0 {
+            return Ok(());
+        }
+        let row_bytes = self.row_bytes();
+        if pitch < row_bytes {
+            return Err(DecoderError::PitchTooSmall {
+                pitch,
+                min_pitch: row_bytes,
+            });
+        }
+        let required = pitch * (self.format.rows(self.height) as usize - 1) + row_bytes;
+        if dst.len() < required {
+            return Err(DecoderError::BufferTooSmall {
+                size: dst.len(),
+                required,
+            });
+        }
+
+        let dst = dst.as_mut_ptr();
+        self.in_context(|| {
+            self.copy_planes(stream, |copy| {
+                copy.dstMemoryType = ffi::cuda::CUmemorytype_enum_CU_MEMORYTYPE_HOST;
+                copy.dstHost = dst as _;
+                copy.dstPitch = pitch as _;
+            })?;
+            match stream {
+                Some(stream) => {
+                    unsafe { self.nvcuvid.cuda.cuStreamSynchronize(stream.inner) }.err()
+                }
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Copies the luma histogram computed while decoding, one counter per bin.
+    ///
+    /// `None` unless the decoder was built with `DecoderBuilder::histogram`.
+    pub fn histogram(&self) -> Result<Option<Vec<u32>>, DecoderError> {
+        let (ptr, layout) = match self.histogram {
+            Some(histogram) => histogram,
+            None => return Ok(None),
+        };
+        let counter_bytes = (layout.counter_bit_depth as usize).div_ceil(8);
+        let mut data = vec![0u8; layout.bins as usize * counter_bytes];
+        self.in_context(|| unsafe {
+            self.nvcuvid
+                .cuda
+                .cuMemcpyDtoH_v2(data.as_mut_ptr() as _, ptr as _, data.len() as _)
+                .err()
+        })?;
+
+        // little endian counters, as wide as the bit depth needs
+        let counters = data
+            .chunks(counter_bytes)
+            .map(|counter| {
+                counter
+                    .iter()
+                    .take(4)
+                    .rev()
+                    .fold(0u32, |value, &byte| value << 8 | byte as u32)
+            })
+            .collect();
+
+        Ok(Some(counters))
+    }
+
+    fn row_bytes(&self) -> usize {
+        (self.width * self.format.bytes_per_sample()) as usize
+    }
+
+    /// Copies every plane to the destination set by `dst`, stacking them.
+    fn copy_planes<F>(&self, stream: Option<&CuStream>, dst: F) -> Result<(), CUresult>
+    where
+        F: Fn(&mut ffi::cuda::CUDA_MEMCPY2D),
+    {
+        let cuda = self.nvcuvid.cuda;
+        for (src_row, rows, dst_row) in self.format.planes(self.height, self.surface_height) {
+            let mut copy: ffi::cuda::CUDA_MEMCPY2D = unsafe { std::mem::zeroed() };
+            copy.srcMemoryType = ffi::cuda::CUmemorytype_enum_CU_MEMORYTYPE_DEVICE;
+            copy.srcDevice = self.ptr as _;
+            copy.srcPitch = self.pitch as _;
+            copy.srcY = src_row as _;
+            dst(&mut copy);
+            copy.dstY = dst_row as _;
+            copy.WidthInBytes = self.row_bytes() as _;
+            copy.Height = rows as _;
+            let res = match stream {
+                Some(stream) => unsafe { cuda.cuMemcpy2DAsync_v2(&copy, stream.inner) },
+                None => unsafe { cuda.cuMemcpy2D_v2(&copy) },
+            };
+            res.err()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f` with the context the frame was mapped in current.
+    fn in_context<F>(&self, f: F) -> Result<(), DecoderError>
+    where
+        F: FnOnce() -> Result<(), CUresult>,
+    {
+        let cuda = self.nvcuvid.cuda;
+        unsafe { cuda.cuCtxPushCurrent_v2(self.context.inner) }
+            .err()
+            .map_err(DecoderError::Cuda)?;
+        let res = f();
+        unsafe { cuda.cuCtxPopCurrent_v2(std::ptr::null_mut()) }
+            .err()
+            .map_err(DecoderError::Cuda)?;
+
+        res.map_err(DecoderError::Cuda)
+    }
 }
 
 impl Drop for GpuFrame<'_> {
     fn drop(&mut self) {
         unsafe {
-            if !self.nvcuvid.cuda.lib.cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
-                tracing::error!("Failed to pop current context.");
+            if !self
+                .nvcuvid
+                .cuda
+                .cuCtxPushCurrent_v2(self.context.inner)
+                .ok()
+            {
+                tracing::error!("Failed to push current context.");
             }
 
-            if !self.nvcuvid.lib.cuvidUnmapVideoFrame64(self.decoder, self.ptr).ok() {
+            if !self
+                .nvcuvid
+                .cuvidUnmapVideoFrame64(self.decoder, self.mapped_ptr)
+                .ok()
+            {
                 tracing::error!("Failed to unmap current frame.");
             }
+
+            if !self
+                .nvcuvid
+                .cuda
+                .cuCtxPopCurrent_v2(std::ptr::null_mut())
+                .ok()
+            {
+                tracing::error!("Failed to pop current context.");
+            }
         }
+        self.mapped_frames.fetch_sub(1, Ordering::AcqRel);
     }
 }
 
-impl Decoder<'_> {
+impl<'a> Decoder<'a> {
     pub(crate) fn new(
-        nvcuvid: &Cuvid,
-        codec: Codec,
-        keyframe_only: bool,
-        low_latency: bool,
-        output_size: (u32, u32),
-        device: Option<CuDevice>,
-        context: Option<CuContext>
-    ) -> Result<Self, ffi::cuda::CUresult> {
-        let device = device.unwrap_or_else(|| {});
-        let context = super::cuda::context::CuContext::new(device, 0)?;
+        nvcuvid: &'a Cuvid<'a>,
+        settings: DecoderSettings,
+        device: Option<CuDevice<'a>>,
+        context: Option<CuContext<'a>>,
+    ) -> Result<Self, DecoderError> {
+        let context = match context {
+            Some(context) => context,
+            None => {
+                let device = match device {
+                    Some(device) => device,
+                    None => nvcuvid.cuda.new_device(0).map_err(DecoderError::Cuda)?,
+                };
+                nvcuvid
+                    .cuda
+                    .new_context(device, 0)
+                    .map_err(DecoderError::Cuda)?
+            }
+        };
 
-        let mut parser: ffi::cuvid::CUvideoparser = std::ptr::null_mut();
         let mut ctx_lock: ffi::cuvid::CUvideoctxlock = std::ptr::null_mut();
 
         unsafe {
-            let res = ffi::cuvid::cuvidCtxLockCreate(&mut ctx_lock, context.context as _);
-            res.err()?;
+            // cuda.h is bound separately for nvcuvid, so the context type has to be cast
+            let res = nvcuvid.cuvidCtxLockCreate(&mut ctx_lock, context.inner as _);
+            res.err().map_err(DecoderError::Cuda)?;
         }
         let (sender, receiver) = flume::unbounded();
 
-        let mut inner = Box::new(Inner {
-            parser,
+        let inner = Box::new(Inner {
+            nvcuvid,
+            parser: Mutex::new(std::ptr::null_mut()),
             context,
-            codec,
             lock: ctx_lock,
-            chroma_format: VideoChromaFormat::Monochrome,
-            decoder: std::ptr::null_mut(),
-            keyframe_only,
-            video_fmt: None,
-            bit_depth_minus8: 0,
-            bpp: 0,
-            output_format: VideoSurfaceFormat::NV12,
-            out_size: (0, 0),
-            coded_size: (0, 0),
-            requested_size: output_size,
+            state: Mutex::new(ParseState {
+                decoder: None,
+                decode_surfaces: 0,
+                max_size: (0, 0),
+                surface_height: 0,
+                film_grain: Vec::new(),
+                sei: Vec::new(),
+                histogram: None,
+                video_fmt: None,
+                codec: settings.codec,
+                chroma_format: VideoChromaFormat::Monochrome,
+                bit_depth_minus8: 0,
+                bpp: 0,
+                output_format: VideoSurfaceFormat::NV12,
+                out_size: (0, 0),
+                coded_size: (0, 0),
+            }),
+            settings,
+            corrupted_frames: AtomicU64::new(0),
+            mapped_frames: AtomicUsize::new(0),
+            pending: Mutex::new(VecDeque::new()),
+            error: Mutex::new(None),
+            format: Mutex::new(None),
             receiver,
-            sender: Some(sender),
+            sender,
         });
+        // from here on dropping the decoder releases everything created so far
+        let decoder = Self {
+            inner,
+            worker: Mutex::new(None),
+        };
 
-        let mut params: ffi::cuvid::CUVIDPARSERPARAMS = unsafe { std::mem::zeroed() };
-        params.CodecType = codec.into();
-        params.ulMaxNumDecodeSurfaces = 1;
-        params.ulClockRate = 10000000;
-        params.ulErrorThreshold = 100;
-        params.ulMaxDisplayDelay = if low_latency { 0 } else { 1 };
-        params.pfnSequenceCallback = Some(handle_video_sequence_proc);
-        params.pfnDecodePicture = Some(handle_picture_decode_proc);
-        params.pfnDisplayPicture = Some(handle_picture_display_proc);
-        params.pfnGetOperatingPoint = Some(handle_operating_point_proc);
-        params.pUserData = (&mut *inner as *mut Inner) as *mut std::os::raw::c_void;
+        decoder.inner.create_parser()?;
 
-        unsafe {
-            let res = ffi::cuvid::cuvidCreateVideoParser(&mut parser, &mut params);
-            res.err()?;
-        }
-        inner.parser = parser;
+        Ok(decoder)
+    }
 
-        Ok(Self { inner })
+    /// Parses `data`, decoding the pictures it completes.
+    ///
+    /// `timestamp` is the presentation timestamp of the picture starting in `data`, see
+    /// `GpuFrame::timestamp`.
+    ///
+    /// An error raised by the parser callbacks is returned here, and also by `frames` after
+    /// the frames displayed before it.
+    pub fn queue(&self, data: &[u8], timestamp: i64) -> Result<(), DecoderError> {
+        self.queue_packet(Packet::new(data).timestamp(timestamp))
     }
 
-    pub fn queue(&self, data: &[u8], timestamp: i64) -> Result<(), ffi::cuda::CUresult> {
-        let mut packet = ffi::cuvid::CUVIDSOURCEDATAPACKET {
-            flags: ffi::cuvid::CUvideopacketflags_CUVID_PKT_TIMESTAMP as _,
-            payload_size: data.len() as u64,
-            payload: data.as_ptr(),
-            timestamp: timestamp,
-        };
+    /// Like `queue`, but tells the parser `data` does not follow the previous packet, as after
+    /// a seek or a transmission loss.
+    pub fn queue_discontinuity(&self, data: &[u8], timestamp: i64) -> Result<(), DecoderError> {
+        self.queue_packet(Packet::new(data).timestamp(timestamp).discontinuity(true))
+    }
 
-        unsafe {
-            let res = ffi::cuvid::cuvidParseVideoData(self.inner.parser, &mut packet);
-            res.err()?;
+    /// Like `queue`, with the flags of `packet`.
+    pub fn queue_packet(&self, packet: Packet) -> Result<(), DecoderError> {
+        self.inner.parse(packet)
+    }
+
+    /// Like `queue_packet`, but parses `packet` on a worker thread so an executor is not blocked.
+    ///
+    /// Each decoder parses on a thread of its own, started by the first call and stopped by
+    /// `flush` or when the decoder is dropped.
+    ///
+    /// Packets are parsed in the order `queue_async` is called, mixing it with `queue` or
+    /// `send_eos` before the returned future completes may reorder them.
+    pub fn queue_async(&self, packet: Packet<'static>) -> QueueFuture {
+        let (done, result) = flume::bounded(1);
+        let job = ParseJob { packet, done };
+
+        let mut worker = self.worker.lock().unwrap_or_else(|e| e.into_inner());
+        let worker = worker.get_or_insert_with(|| ParseWorker::new(&self.inner));
+        // if the worker is gone the job is dropped with its sender and the future fails
+        let _ = worker.jobs.send(job);
+
+        QueueFuture {
+            result: result.into_recv_async(),
         }
+    }
 
-        Ok(())
+    /// Displays every picture queued so far, `frames` ends after them.
+    ///
+    /// The decoder can be fed again afterwards, starting at a keyframe.
+    pub fn send_eos(&self) -> Result<(), DecoderError> {
+        self.queue_packet(Packet::new(&[]).end_of_stream(true))
     }
 
-    pub fn send_eos(&self) -> Result<(), ffi::cuda::CUresult> {
-        let mut packet: ffi::cuvid::CUVIDSOURCEDATAPACKET = unsafe { std::mem::zeroed() };
-        packet.flags = (ffi::cuvid::CUvideopacketflags_CUVID_PKT_ENDOFSTREAM
-            | ffi::cuvid::CUvideopacketflags_CUVID_PKT_NOTIFY_EOS) as _;
+    /// Discards every picture queued so far and resets the parser, for seeking.
+    ///
+    /// Decoding resumes at the next keyframe queued, the decoder itself is kept.
+    pub fn flush(&mut self) -> Result<(), DecoderError> {
+        self.stop_worker();
+
+        let res = self.queue_packet(Packet::new(&[]).end_of_stream(true));
+
+        self.inner.receiver.drain();
+        self.inner.lock_pending().clear();
+        self.inner.lock_state().sei.iter_mut().for_each(Vec::clear);
 
         unsafe {
-            let res = ffi::cuvid::cuvidParseVideoData(self.inner.parser, &mut packet);
-            res.err()?;
+            let mut parser = self.inner.lock_parser();
+            self.inner.nvcuvid.cuvidDestroyVideoParser(*parser);
+            *parser = std::ptr::null_mut();
         }
+        self.inner.create_parser()?;
 
-        Ok(())
+        res
     }
 
-    pub fn frames<'a, 'b>(
-        &'a self,
-        context: Option<&'b super::cuda::context::CuContext>,
-    ) -> FramesIter<'a, 'b> {
+    /// Format of the current sequence, `None` until the first sequence header is parsed.
+    pub fn video_format(&self) -> Option<VideoFormat> {
+        *self.inner.format.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Number of frames displayed so far that were decoded with errors, dropped ones included.
+    pub fn corrupted_frames(&self) -> u64 {
+        self.inner.corrupted_frames.load(Ordering::Relaxed)
+    }
+
+    /// Events of the queued packets, ending after the frames displayed by `send_eos`.
+    ///
+    /// Frames are mapped in `context` if given, so they cannot outlive it, or in the decoder's.
+    ///
+    /// ```compile_fail,E0597
+    /// # use nvidia_video_codec::cuda::Cuda;
+    /// # use nvidia_video_codec::cuvid::{Codec, Cuvid};
+    /// let cuda = Cuda::new().unwrap();
+    /// let cuvid = Cuvid::new(&cuda).unwrap();
+    /// let decoder = cuvid.decoder_builder(Codec::H264).build().unwrap();
+    /// let event = {
+    ///     let ctx = cuda.new_context(cuda.new_device(0).unwrap(), 0).unwrap();
+    ///     decoder.frames(Some(&ctx)).next()
+    /// };
+    /// ```
+    pub fn frames<'f, 'b: 'f>(&'f self, context: Option<&'b CuContext<'b>>) -> FramesIter<'f, 'b> {
         FramesIter {
             inner: &self.inner,
             context,
         }
     }
+
+    /// Asynchronous `frames`, waiting for the next event without blocking the executor.
+    pub fn frames_stream<'f, 'b: 'f>(
+        &'f self,
+        context: Option<&'b CuContext<'b>>,
+    ) -> FramesStream<'f, 'b> {
+        FramesStream {
+            inner: &self.inner,
+            context,
+            messages: self.inner.receiver.stream(),
+        }
+    }
+}
+
+impl Decoder<'_> {
+    /// Waits for the `queue_async` worker to parse its packets and stops it.
+    fn stop_worker(&mut self) {
+        let worker = self
+            .worker
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(ParseWorker { jobs, thread }) = worker {
+            drop(jobs);
+            if thread.join().is_err() {
+                tracing::error!("Parse worker panicked.");
+            }
+        }
+    }
 }
 
-impl Drop for Decoder {
+impl Drop for Decoder<'_> {
     fn drop(&mut self) {
+        // the worker parses the queued packets and stops before the parser goes away
+        self.stop_worker();
+
+        let nvcuvid = self.inner.nvcuvid;
         unsafe {
-            ffi::cuvid::cuvidDestroyVideoParser(self.inner.parser);
+            let parser = *self.inner.lock_parser();
+            if !parser.is_null() {
+                nvcuvid.cuvidDestroyVideoParser(parser);
+            }
+
+            // the decoders go away with the last frame of theirs, before their context
+            self.inner.receiver.drain();
+            self.inner.lock_pending().clear();
+            self.inner.lock_state().decoder = None;
+            nvcuvid.cuvidCtxLockDestroy(self.inner.lock);
+        }
+    }
+}
+
+struct ParseJob {
+    packet: Packet<'static>,
+    done: flume::Sender<Result<(), DecoderError>>,
+}
+
+// `Inner` is boxed and outlives the worker, which `Decoder` joins when dropped
+struct InnerPtr(*const std::os::raw::c_void);
 
-            if !self.inner.decoder.is_null() {
-                ffi::cuda::cuCtxPushCurrent_v2(self.inner.context.context);
-                ffi::cuvid::cuvidDestroyDecoder(self.inner.decoder);
+unsafe impl Send for InnerPtr {}
+
+struct ParseWorker {
+    jobs: flume::Sender<ParseJob>,
+    thread: JoinHandle<()>,
+}
+
+impl ParseWorker {
+    fn new(inner: &Inner) -> Self {
+        let (jobs, receiver) = flume::unbounded::<ParseJob>();
+        let inner = InnerPtr(inner as *const Inner as *const std::os::raw::c_void);
+        let thread = std::thread::spawn(move || {
+            let inner = unsafe { &*(inner.0 as *const Inner) };
+            for job in receiver {
+                let _ = job.done.send(inner.parse(job.packet));
             }
-            ffi::cuda::cuCtxPopCurrent_v2(std::ptr::null_mut());
-            ffi::cuvid::cuvidCtxLockDestroy(self.inner.lock);
+        });
+
+        Self { jobs, thread }
+    }
+}
+
+/// Completion of a `Decoder::queue_async`, resolving to what `queue` would have returned.
+pub struct QueueFuture {
+    result: flume::async::RecvFut<'static, Result<(), DecoderError>>,
+}
+
+impl Future for QueueFuture {
+    type Output = Result<(), DecoderError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match Pin::new(&mut self.result).poll(cx) {
+            Poll::Ready(Ok(res)) => Poll::Ready(res),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(DecoderError::WorkerStopped)),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-impl Inner {
-    fn sequence_cb(&mut self, video_fmt: *mut ffi::cuvid::CUVIDEOFORMAT) -> i32 {
+impl Inner<'_> {
+    fn create_parser(&self) -> Result<(), DecoderError> {
+        let mut params: ffi::cuvid::CUVIDPARSERPARAMS = unsafe { std::mem::zeroed() };
+        params.CodecType = self.settings.codec.into();
+        params.ulMaxNumDecodeSurfaces = 1;
+        params.ulClockRate = self.settings.clock_rate;
+        params.ulErrorThreshold = 100;
+        params.ulMaxDisplayDelay = if self.settings.low_latency { 0 } else { 1 };
+        params.pfnSequenceCallback = Some(handle_video_sequence_proc);
+        params.pfnDecodePicture = Some(handle_picture_decode_proc);
+        params.pfnDisplayPicture = Some(handle_picture_display_proc);
+        params.pfnGetOperatingPoint = Some(handle_operating_point_proc);
+        params.pfnGetSEIMsg = Some(handle_sei_msg_proc);
+        // the callbacks only get a shared `Inner`, whatever they change is behind its mutexes
+        params.pUserData = (self as *const Inner) as *mut std::os::raw::c_void;
+
+        let mut parser: ffi::cuvid::CUvideoparser = std::ptr::null_mut();
+        unsafe {
+            let res = self
+                .nvcuvid
+                .cuvidCreateVideoParser(&mut parser, &mut params);
+            res.err().map_err(DecoderError::Cuda)?;
+        }
+        *self.lock_parser() = parser;
+
+        Ok(())
+    }
+
+    fn parse(&self, packet: Packet) -> Result<(), DecoderError> {
+        // every MJPEG packet is a whole picture, decoded without waiting for the next one
+        let packet = match self.settings.codec {
+            Codec::JPEG => packet.end_of_picture(true),
+            _ => packet,
+        };
+        let mut packet = packet.raw();
+
+        let parser = self.lock_parser();
+        let res = unsafe { self.nvcuvid.cuvidParseVideoData(*parser, &mut packet) };
+        if let Some(err) = self.take_error() {
+            return Err(err);
+        }
+
+        res.err().map_err(DecoderError::Cuda)
+    }
+
+    /// Runs a parser callback, keeping its error or panic for `queue` and `frames` instead of
+    /// letting it unwind into the parser, which is handed `failure` then.
+    fn callback<F>(&self, failure: i32, f: F) -> i32
+    where
+        F: FnOnce(&Self, &mut ParseState) -> Result<i32, DecoderError>,
+    {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(self, &mut self.lock_state())));
+        let err = match res {
+            Ok(Ok(res)) => return res,
+            Ok(Err(err)) => err,
+            Err(payload) => {
+                let message = match payload.downcast::<String>() {
+                    Ok(message) => *message,
+                    Err(payload) => match payload.downcast::<&str>() {
+                        Ok(message) => message.to_string(),
+                        Err(_) => String::from("unknown panic"),
+                    },
+                };
+                DecoderError::Panic(message)
+            }
+        };
+        tracing::error!("{}", err);
+
+        // the parser keeps calling back after a failure, the first error is the one to report
+        let mut error = self.error.lock().unwrap_or_else(|e| e.into_inner());
+        if error.is_none() {
+            let _ = self.sender.send(Message::Error(err.clone()));
+            *error = Some(err);
+        }
+
+        failure
+    }
+
+    fn lock_parser(&self) -> MutexGuard<'_, ffi::cuvid::CUvideoparser> {
+        self.parser.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ParseState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, VecDeque<(Box<PreparedFrame>, Option<DecodeStatus>)>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes an output surface for a new `GpuFrame`, `false` if all are mapped.
+    fn reserve_mapping(&self) -> bool {
+        let limit = self.settings.output_surfaces as usize;
+        self.mapped_frames
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |mapped| {
+                if mapped < limit {
+                    Some(mapped + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    fn take_error(&self) -> Option<DecoderError> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    /// Runs `f` with the decoder's context current.
+    fn in_context<F>(&self, f: F) -> Result<(), DecoderError>
+    where
+        F: FnOnce() -> CUresult,
+    {
+        unsafe {
+            self.nvcuvid
+                .cuda
+                .cuCtxPushCurrent_v2(self.context.inner)
+                .err()
+                .map_err(DecoderError::Cuda)?;
+        }
+        let res = f();
+        unsafe {
+            self.nvcuvid
+                .cuda
+                .cuCtxPopCurrent_v2(std::ptr::null_mut())
+                .err()
+                .map_err(DecoderError::Cuda)?;
+        }
+
+        res.err().map_err(DecoderError::Cuda)
+    }
+
+    fn sequence_cb(
+        &self,
+        state: &mut ParseState,
+        video_fmt: *mut ffi::cuvid::CUVIDEOFORMAT,
+    ) -> Result<i32, DecoderError> {
         let fmt = unsafe { &*video_fmt };
 
         tracing::debug!(
@@ -268,292 +978,708 @@ impl Inner {
         );
 
         let min_surfaces = fmt.min_num_decode_surfaces;
+        let codec = Codec::try_from(fmt.codec)?;
+        let chroma_format = VideoChromaFormat::try_from(fmt.chroma_format)?;
 
-        let mut decode_caps: ffi::cuvid::CUVIDDECODECAPS = unsafe { std::mem::zeroed() };
-        decode_caps.eCodecType = fmt.codec;
-        decode_caps.eChromaFormat = fmt.chroma_format;
-        decode_caps.nBitDepthMinus8 = fmt.bit_depth_chroma_minus8 as _;
+        let decode_caps = self.nvcuvid.decoder_caps(
+            &self.context,
+            codec,
+            chroma_format,
+            fmt.bit_depth_chroma_minus8,
+        )?;
+
+        if !decode_caps.supported {
+            return Err(DecoderError::Unsupported {
+                codec,
+                chroma_format,
+                bit_depth_minus8: fmt.bit_depth_chroma_minus8,
+            });
+        }
 
-        unsafe {
-            if !ffi::cuda::cuCtxPushCurrent_v2(self.context.context).ok() {
-                return min_surfaces as _;
-            }
-            if !ffi::cuvid::cuvidGetDecoderCaps(&mut decode_caps).ok() {
-                return min_surfaces as _;
-            }
-            if !ffi::cuda::cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
-                return min_surfaces as _;
-            }
+        if (fmt.coded_width > decode_caps.max_size.0) || (fmt.coded_height > decode_caps.max_size.1)
+        {
+            return Err(DecoderError::SizeTooLarge {
+                size: (fmt.coded_width, fmt.coded_height),
+                max_size: decode_caps.max_size,
+            });
+        }
+        if (fmt.coded_width >> 4) * (fmt.coded_height >> 4) > decode_caps.max_macroblocks {
+            return Err(DecoderError::TooManyMacroblocks {
+                count: (fmt.coded_width >> 4) * (fmt.coded_height >> 4),
+                max_count: decode_caps.max_macroblocks,
+            });
         }
 
-        if decode_caps.bIsSupported == 0 {
-            tracing::error!("Codec not supported on this GPU");
-            return min_surfaces as _;
+        state.codec = codec;
+        state.chroma_format = chroma_format;
+        state.bit_depth_minus8 = fmt.bit_depth_luma_minus8;
+        state.bpp = if fmt.bit_depth_luma_minus8 > 0 { 2 } else { 1 };
+
+        let previous_output_format = state.output_format;
+        let output_format = match self.settings.output_format {
+            Some(format) if decode_caps.supports_output_format(format) => Some(format),
+            Some(_) => None,
+            None => state.auto_output_format(&decode_caps),
+        };
+        state.output_format = output_format.ok_or(DecoderError::UnsupportedOutputFormat(
+            self.settings.output_format,
+        ))?;
+        state.histogram = match self.settings.histogram {
+            true if !decode_caps.histogram_supported => {
+                return Err(DecoderError::HistogramUnsupported(codec));
+            }
+            true => Some(HistogramLayout {
+                bins: decode_caps.histogram_max_bins,
+                counter_bit_depth: decode_caps.histogram_counter_bit_depth,
+            }),
+            false => None,
+        };
+
+        let previous_fmt = state.video_fmt.replace(*fmt);
+        let video_fmt = *fmt;
+
+        if state.decoder.is_none() {
+            self.update_output_size(state, &video_fmt);
+            let surfaces = self.create_decoder(state, &video_fmt, min_surfaces)?;
+            self.update_format(state, &video_fmt);
+            return Ok(surfaces);
         }
 
-        if (fmt.coded_width > decode_caps.nMaxWidth) || (fmt.coded_height > decode_caps.nMaxHeight)
-        {
-            tracing::error!(
-                "Resolution {}x{} if greater than max resolution {}x{} for the GPU",
-                fmt.coded_width,
-                fmt.coded_height,
-                decode_caps.nMaxWidth,
-                decode_caps.nMaxHeight
+        // the parser reports every sequence header, most of them repeat the current one
+        let previous_fmt = match previous_fmt {
+            Some(previous_fmt) if !sequence_changed(&previous_fmt, &video_fmt) => {
+                return Ok(state.decode_surfaces as _);
+            }
+            Some(previous_fmt) => previous_fmt,
+            None => video_fmt,
+        };
+        self.update_output_size(state, &video_fmt);
+
+        let reconfigurable = previous_fmt.bit_depth_luma_minus8 == video_fmt.bit_depth_luma_minus8
+            && previous_fmt.chroma_format == video_fmt.chroma_format
+            && previous_fmt.progressive_sequence == video_fmt.progressive_sequence
+            && previous_output_format == state.output_format
+            && video_fmt.coded_width <= state.max_size.0
+            && video_fmt.coded_height <= state.max_size.1
+            && min_surfaces as u64 <= state.decode_surfaces
+            // queued and mapped frames keep the geometry they were decoded with, so a decoder
+            // with frames left is replaced instead of resized under them
+            && state
+                .decoder
+                .as_ref()
+                .is_some_and(|decoder| Arc::strong_count(decoder) == 1);
+
+        let surfaces = if reconfigurable {
+            tracing::debug!(
+                "Reconfiguring decoder for {}x{}",
+                video_fmt.coded_width,
+                video_fmt.coded_height
             );
-            return min_surfaces as _;
-        }
-        if (fmt.coded_width >> 4) * (fmt.coded_height >> 4) > decode_caps.nMaxMBCount {
-            tracing::error!(
-                "bitrate {} if greater than max bitrate {} for the GPU",
-                (fmt.coded_width >> 4) * (fmt.coded_height >> 4),
-                decode_caps.nMaxMBCount
+            self.reconfigure_decoder(state, &video_fmt)?
+        } else {
+            tracing::debug!(
+                "Recreating decoder for {}x{}",
+                video_fmt.coded_width,
+                video_fmt.coded_height
             );
+            // the frames left hold the old decoder until they are dropped
+            state.decoder = None;
+            self.create_decoder(state, &video_fmt, min_surfaces)?
+        };
 
-            return min_surfaces as _;
-        }
+        let format = self.update_format(state, &video_fmt);
+        let _ = self.sender.send(Message::SequenceChanged(format));
+
+        Ok(surfaces)
+    }
 
-        self.codec = fmt.codec.into();
-        self.chroma_format = fmt.chroma_format.into();
-        self.bit_depth_minus8 = fmt.bit_depth_luma_minus8;
-        self.bpp = if fmt.bit_depth_luma_minus8 > 0 { 2 } else { 1 };
+    fn update_format(
+        &self,
+        state: &mut ParseState,
+        video_fmt: &ffi::cuvid::CUVIDEOFORMAT,
+    ) -> VideoFormat {
+        let format = VideoFormat {
+            codec: state.codec,
+            chroma_format: state.chroma_format,
+            bit_depth_minus8: state.bit_depth_minus8,
+            coded_size: (video_fmt.coded_width, video_fmt.coded_height),
+            display_area: Rect::new(
+                video_fmt.display_area.left as _,
+                video_fmt.display_area.top as _,
+                video_fmt.display_area.right as _,
+                video_fmt.display_area.bottom as _,
+            ),
+            output_size: state.out_size,
+            output_format: state.output_format,
+            frame_rate: (
+                video_fmt.frame_rate.numerator,
+                video_fmt.frame_rate.denominator,
+            ),
+            progressive: video_fmt.progressive_sequence != 0,
+            display_aspect_ratio: (
+                video_fmt.display_aspect_ratio.x.max(0) as _,
+                video_fmt.display_aspect_ratio.y.max(0) as _,
+            ),
+            bitrate: video_fmt.bitrate,
+            signal: VideoSignalDescription::from(video_fmt),
+        };
+        *self.format.lock().unwrap_or_else(|e| e.into_inner()) = Some(format);
 
-        if false {
-            if self.chroma_format == VideoChromaFormat::YUV420
-                || self.chroma_format == VideoChromaFormat::Monochrome
-            {
-                self.output_format = if self.bit_depth_minus8 != 0 {
-                    VideoSurfaceFormat::P016
-                } else {
-                    VideoSurfaceFormat::NV12
-                }
-            } else if self.chroma_format == VideoChromaFormat::YUV444 {
-                self.output_format = if self.bit_depth_minus8 != 0 {
-                    VideoSurfaceFormat::YUV444_16
-                } else {
-                    VideoSurfaceFormat::YUV444
-                }
-            } else if self.chroma_format == VideoChromaFormat::YUV422 {
-                self.output_format = VideoSurfaceFormat::NV12
-            }
+        format
+    }
 
-            // Check if output format supported. If not, check falback options
-            if (decode_caps.nOutputFormatMask & (1 << (self.output_format as u16))) == 0 {
-                if decode_caps.nOutputFormatMask & (1 << (VideoSurfaceFormat::NV12 as u16)) != 0 {
-                    self.output_format = VideoSurfaceFormat::NV12;
-                } else if decode_caps.nOutputFormatMask & (1 << (VideoSurfaceFormat::P016 as u16))
-                    != 0
-                {
-                    self.output_format = VideoSurfaceFormat::P016;
-                } else if decode_caps.nOutputFormatMask & (1 << (VideoSurfaceFormat::YUV444 as u16))
-                    != 0
-                {
-                    self.output_format = VideoSurfaceFormat::YUV444;
-                } else if decode_caps.nOutputFormatMask
-                    & (1 << (VideoSurfaceFormat::YUV444_16 as u16))
-                    != 0
-                {
-                    self.output_format = VideoSurfaceFormat::YUV444_16;
-                } else {
-                    panic!("No supported output format found");
-                }
-            }
+    fn update_output_size(&self, state: &mut ParseState, video_fmt: &ffi::cuvid::CUVIDEOFORMAT) {
+        if let Some(geometry) = self.geometry(video_fmt) {
+            state.out_size = geometry.target_size;
+            state.coded_size = state.out_size;
         } else {
-            /*
-                The above ouptut format selection was copied from NvDecoder.cpp
-                in the Video Codec SDK samples; however OpenCV with usage
-                of GPU Mat always selects NV12
-            */
+            state.out_size.0 = (video_fmt.display_area.right - video_fmt.display_area.left) as _;
+            state.out_size.1 = (video_fmt.display_area.bottom - video_fmt.display_area.top) as _;
+            state.coded_size = (video_fmt.coded_width, video_fmt.coded_height);
+        }
+    }
 
-            self.output_format = VideoSurfaceFormat::NV12;
-            if decode_caps.nOutputFormatMask & (1 << (VideoSurfaceFormat::NV12 as u16)) == 0 {
-                tracing::error!("The output format NV12 is not supported by this decoder.");
-                // should we blow up here?
-                return 0;
-            }
+    /// Where the decoder scales from and to, `None` when the coded picture is output as is.
+    fn geometry(&self, video_fmt: &ffi::cuvid::CUVIDEOFORMAT) -> Option<Geometry> {
+        let requested_size = self.settings.output_size;
+        let scaled = requested_size.0 > 0 && requested_size.1 > 0;
+        if !scaled && self.settings.crop.is_none() && self.settings.target_rect.is_none() {
+            return None;
         }
 
-        self.video_fmt = Some(*fmt);
-        let video_fmt = self.video_fmt.as_ref().unwrap();
-        let decode_surfaces = (min_surfaces as u64).max(12);
+        let area = self.settings.crop.unwrap_or_else(|| {
+            Rect::new(
+                video_fmt.display_area.left as _,
+                video_fmt.display_area.top as _,
+                video_fmt.display_area.right as _,
+                video_fmt.display_area.bottom as _,
+            )
+        });
+        let size = if scaled {
+            requested_size
+        } else {
+            (area.width(), area.height())
+        };
+
+        Some(Geometry::new(
+            area,
+            size,
+            self.settings.scale_mode,
+            self.settings.target_rect,
+        ))
+    }
+
+    fn create_decoder(
+        &self,
+        state: &mut ParseState,
+        video_fmt: &ffi::cuvid::CUVIDEOFORMAT,
+        min_surfaces: u8,
+    ) -> Result<i32, DecoderError> {
+        let decode_surfaces = match self.settings.decode_surfaces {
+            Some(count) => (min_surfaces as u64).max(count as u64),
+            None => (min_surfaces as u64).max(12),
+        };
+        let max_size = (
+            video_fmt.coded_width.max(self.settings.max_size.0),
+            video_fmt.coded_height.max(self.settings.max_size.1),
+        );
 
         let mut video_decode_create_info: ffi::cuvid::CUVIDDECODECREATEINFO =
             unsafe { std::mem::zeroed() };
 
-        video_decode_create_info.CodecType = self.codec.into();
-        video_decode_create_info.ChromaFormat = self.chroma_format.into();
-        video_decode_create_info.OutputFormat = self.output_format.into();
+        video_decode_create_info.CodecType = state.codec.into();
+        video_decode_create_info.ChromaFormat = state.chroma_format.into();
+        video_decode_create_info.OutputFormat = state.output_format.into();
         video_decode_create_info.bitDepthMinus8 = video_fmt.bit_depth_luma_minus8 as _;
-        video_decode_create_info.DeinterlaceMode = if video_fmt.progressive_sequence != 0 {
-            ffi::cuvid::cudaVideoDeinterlaceMode_enum_cudaVideoDeinterlaceMode_Weave
-        } else {
-            ffi::cuvid::cudaVideoDeinterlaceMode_enum_cudaVideoDeinterlaceMode_Adaptive
-        };
-        video_decode_create_info.ulNumOutputSurfaces = 3;
+        video_decode_create_info.DeinterlaceMode = self
+            .settings
+            .field_output
+            .deinterlace_mode(
+                video_fmt.progressive_sequence != 0,
+                self.settings.deinterlace_mode,
+            )
+            .into();
+        video_decode_create_info.ulNumOutputSurfaces = self.settings.output_surfaces as _;
+        video_decode_create_info.enableHistogram = self.settings.histogram as _;
         video_decode_create_info.ulCreationFlags =
-            ffi::cuvid::cudaVideoCreateFlags_enum_cudaVideoCreate_PreferCUVID as _;
+            ffi::cuvid::cudaVideoCreateFlags::from(self.settings.create_flags) as _;
         video_decode_create_info.ulNumDecodeSurfaces = decode_surfaces;
         video_decode_create_info.vidLock = self.lock;
         video_decode_create_info.ulWidth = video_fmt.coded_width as _;
         video_decode_create_info.ulHeight = video_fmt.coded_height as _;
-        video_decode_create_info.ulMaxWidth = video_fmt.coded_width as _;
-        video_decode_create_info.ulMaxHeight = video_fmt.coded_height as _;
-        video_decode_create_info.ulTargetWidth = video_fmt.coded_width as _;
-        video_decode_create_info.ulTargetHeight = video_fmt.coded_height as _;
-        video_decode_create_info.ulIntraDecodeOnly = if self.keyframe_only { 1 } else { 0 };
-
-        if self.requested_size.0 > 0 && self.requested_size.1 > 0 {
-            video_decode_create_info.display_area.left = video_fmt.display_area.left as _;
-            video_decode_create_info.display_area.top = video_fmt.display_area.top as _;
-            video_decode_create_info.display_area.right = video_fmt.display_area.right as _;
-            video_decode_create_info.display_area.bottom = video_fmt.display_area.bottom as _;
-
-            self.out_size = self.requested_size;
-            video_decode_create_info.ulTargetWidth = self.out_size.0 as _;
-            video_decode_create_info.ulTargetHeight = self.out_size.1 as _;
-            self.coded_size = self.out_size;
-        } else {
-            self.out_size.0 = (video_fmt.display_area.right - video_fmt.display_area.left) as _;
-            self.out_size.1 = (video_fmt.display_area.bottom - video_fmt.display_area.top) as _;
-            self.coded_size = (video_fmt.coded_width, video_fmt.coded_height);
-        }
-        unsafe {
-            if !ffi::cuda::cuCtxPushCurrent_v2(self.context.context).ok() {
-                return min_surfaces as _;
-            }
-            if !ffi::cuvid::cuvidCreateDecoder(&mut self.decoder, &mut video_decode_create_info)
-                .ok()
-            {
-                return min_surfaces as _;
+        video_decode_create_info.ulMaxWidth = max_size.0 as _;
+        video_decode_create_info.ulMaxHeight = max_size.1 as _;
+        video_decode_create_info.ulIntraDecodeOnly =
+            if self.settings.keyframe_only { 1 } else { 0 };
+
+        let target_size = match self.geometry(video_fmt) {
+            Some(geometry) => {
+                let area = geometry.display_area;
+                video_decode_create_info.display_area.left = area.left;
+                video_decode_create_info.display_area.top = area.top;
+                video_decode_create_info.display_area.right = area.right;
+                video_decode_create_info.display_area.bottom = area.bottom;
+                if let Some(rect) = geometry.target_rect {
+                    video_decode_create_info.target_rect.left = rect.left;
+                    video_decode_create_info.target_rect.top = rect.top;
+                    video_decode_create_info.target_rect.right = rect.right;
+                    video_decode_create_info.target_rect.bottom = rect.bottom;
+                }
+                geometry.target_size
             }
-            if !ffi::cuda::cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
-                return min_surfaces as _;
+            None => (video_fmt.coded_width, video_fmt.coded_height),
+        };
+        video_decode_create_info.ulTargetWidth = target_size.0 as _;
+        video_decode_create_info.ulTargetHeight = target_size.1 as _;
+        state.surface_height = target_size.1;
+
+        let mut decoder = std::ptr::null_mut();
+        self.in_context(|| unsafe {
+            self.nvcuvid
+                .cuvidCreateDecoder(&mut decoder, &mut video_decode_create_info)
+        })?;
+        state.decoder = Some(Arc::new(SharedDecoder {
+            nvcuvid: self.nvcuvid.lib.clone(),
+            cuda: self.nvcuvid.cuda.lib.clone(),
+            context: self.context.inner,
+            inner: decoder,
+        }));
+        state.decode_surfaces = decode_surfaces;
+        state.max_size = max_size;
+
+        Ok(decode_surfaces as _)
+    }
+
+    /// Resizes the current decoder in place, only valid within the size it was created for.
+    fn reconfigure_decoder(
+        &self,
+        state: &mut ParseState,
+        video_fmt: &ffi::cuvid::CUVIDEOFORMAT,
+    ) -> Result<i32, DecoderError> {
+        let decoder = state.raw_decoder()?;
+        let mut params: ffi::cuvid::CUVIDRECONFIGUREDECODERINFO = unsafe { std::mem::zeroed() };
+        params.ulWidth = video_fmt.coded_width;
+        params.ulHeight = video_fmt.coded_height;
+        params.ulNumDecodeSurfaces = state.decode_surfaces as _;
+
+        let target_size = match self.geometry(video_fmt) {
+            Some(geometry) => {
+                let area = geometry.display_area;
+                params.display_area.left = area.left;
+                params.display_area.top = area.top;
+                params.display_area.right = area.right;
+                params.display_area.bottom = area.bottom;
+                if let Some(rect) = geometry.target_rect {
+                    params.target_rect.left = rect.left;
+                    params.target_rect.top = rect.top;
+                    params.target_rect.right = rect.right;
+                    params.target_rect.bottom = rect.bottom;
+                }
+                geometry.target_size
             }
+            None => (video_fmt.coded_width, video_fmt.coded_height),
+        };
+        params.ulTargetWidth = target_size.0 as _;
+        params.ulTargetHeight = target_size.1 as _;
+        state.surface_height = target_size.1;
+
+        self.in_context(|| unsafe { self.nvcuvid.cuvidReconfigureDecoder(decoder, &mut params) })?;
+
+        Ok(state.decode_surfaces as _)
+    }
+
+    fn picture_decode_cb(
+        &self,
+        state: &mut ParseState,
+        pic_params: *mut ffi::cuvid::CUVIDPICPARAMS,
+    ) -> Result<i32, DecoderError> {
+        let decoder = state.raw_decoder()?;
+        let params = unsafe { &*pic_params };
+        let index = params.CurrPicIdx.max(0) as usize;
+        if index >= state.film_grain.len() {
+            state.film_grain.resize(index + 1, false);
         }
+        state.film_grain[index] =
+            state.codec == Codec::AV1 && unsafe { params.CodecSpecific.av1.apply_grain() } != 0;
+
+        self.in_context(|| unsafe { self.nvcuvid.cuvidDecodePicture(decoder, pic_params) })?;
 
-        return decode_surfaces as _;
+        Ok(1)
     }
 
-    fn picture_decode_cb(&self, pic_params: *mut ffi::cuvid::CUVIDPICPARAMS) -> i32 {
-        if self.decoder.is_null() {
-            panic!("decoder not initialized");
+    fn picture_display_cb(
+        &self,
+        state: &mut ParseState,
+        display_info: *mut ffi::cuvid::CUVIDPARSERDISPINFO,
+    ) -> Result<i32, DecoderError> {
+        if display_info.is_null() {
+            let _ = self.sender.send(Message::EndOfStream);
+            return Ok(1);
         }
-        unsafe {
-            if !ffi::cuda::cuCtxPushCurrent_v2(self.context.context).ok() {
-                return 0;
-            }
-            if !ffi::cuvid::cuvidDecodePicture(self.decoder, pic_params).ok() {
-                return 0;
-            }
-            // low latency option
-            if !ffi::cuda::cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
-                return 0;
+        let decoder = state.decoder.clone().ok_or(DecoderError::NotInitialized)?;
+        let display_info = unsafe { &*display_info };
+        let index = display_info.picture_index;
+        let fields = self.settings.field_output.fields(
+            display_info.progressive_frame != 0,
+            display_info.top_field_first != 0,
+            display_info.repeat_first_field,
+        );
+        let field_duration = state.video_fmt.map(|fmt| fmt.frame_rate).map_or(0, |rate| {
+            deinterlace::field_duration(
+                self.settings.clock_rate,
+                (rate.numerator, rate.denominator),
+            )
+        });
+        let film_grain = state
+            .film_grain
+            .get(index as usize)
+            .cloned()
+            .unwrap_or(false);
+        let mut sei = state
+            .sei
+            .get_mut(index as usize)
+            .map(std::mem::take)
+            .unwrap_or_default();
+
+        for (position, (second_field, field)) in fields.into_iter().enumerate() {
+            let mut parameters: ffi::cuvid::CUVIDPROCPARAMS = unsafe { std::mem::zeroed() };
+            parameters.progressive_frame = display_info.progressive_frame;
+            parameters.second_field = second_field;
+            parameters.top_field_first = display_info.top_field_first;
+            parameters.unpaired_field = (display_info.repeat_first_field < 0) as i32;
+
+            let res = self.sender.send(Message::Frame(Box::new(PreparedFrame {
+                index,
+                parameters,
+                timestamp: display_info.timestamp + position as i64 * field_duration,
+                decoder: decoder.clone(),
+                size: state.out_size,
+                surface_height: state.surface_height,
+                format: state.output_format,
+                film_grain,
+                sei: std::mem::take(&mut sei),
+                field,
+                histogram: state.histogram,
+            })));
+            if res.is_err() {
+                return Ok(0);
             }
         }
 
-        1
+        Ok(1)
     }
 
-    fn picture_display_cb(&mut self, display_info: *mut ffi::cuvid::CUVIDPARSERDISPINFO) -> i32 {
-        if display_info.is_null() {
-            drop(self.sender.take());
-            return 1;
+    /// Keeps the SEI messages of the picture about to be decoded until it is displayed.
+    fn sei_msg_cb(
+        &self,
+        state: &mut ParseState,
+        sei_info: *mut ffi::cuvid::CUVIDSEIMESSAGEINFO,
+    ) -> Result<i32, DecoderError> {
+        let sei_info = unsafe { &*sei_info };
+        let count = sei_info.sei_message_count as usize;
+        if sei_info.pSEIMessage.is_null() || sei_info.pSEIData.is_null() || count == 0 {
+            return Ok(1);
         }
-        if self.sender.is_none() {
-            return 1;
+        let messages = unsafe { std::slice::from_raw_parts(sei_info.pSEIMessage, count) };
+        let size = messages
+            .iter()
+            .map(|message| message.sei_message_size as usize)
+            .sum();
+        let data = unsafe { std::slice::from_raw_parts(sei_info.pSEIData as *const u8, size) };
+
+        let mut offset = 0;
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let payload = &data[offset..offset + message.sei_message_size as usize];
+                offset += payload.len();
+                SeiMessage::parse(state.codec, message.sei_message_type, payload)
+            })
+            .collect();
+
+        let index = sei_info.picIdx as usize;
+        if index >= state.sei.len() {
+            state.sei.resize(index + 1, Vec::new());
         }
-        let display_info = unsafe { &*display_info };
-        let video_processing_parameters = {
-            let mut video_processing_parameters: ffi::cuvid::CUVIDPROCPARAMS =
-                unsafe { std::mem::zeroed() };
-            video_processing_parameters.progressive_frame = display_info.progressive_frame;
-            video_processing_parameters.second_field = display_info.repeat_first_field + 1;
-            video_processing_parameters.top_field_first = display_info.top_field_first;
-            video_processing_parameters.unpaired_field =
-                (display_info.repeat_first_field < 0) as i32;
-
-            video_processing_parameters
-        };
+        state.sei[index] = messages;
 
-        let res = self.sender.as_ref().unwrap().send(PreparedFrame {
-            index: display_info.picture_index,
-            parameters: video_processing_parameters,
-            timestamp: display_info.timestamp,
-        });
-        if let Err(_) = res {
-            return 0;
+        Ok(1)
+    }
+
+    /// Picks the AV1 operating point, returned as the index with "output all layers" in bit 10.
+    fn operating_point_cb(
+        &self,
+        op_info: *mut ffi::cuvid::CUVIDOPERATINGPOINTINFO,
+    ) -> Result<i32, DecoderError> {
+        let op_info = unsafe { &*op_info };
+        if op_info.codec != ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_AV1 {
+            return Ok(0);
         }
-        return 1;
+        let count = unsafe { op_info.__bindgen_anon_1.av1.operating_points_cnt } as u32;
+        let operating_point = if self.settings.operating_point < count {
+            self.settings.operating_point
+        } else {
+            tracing::warn!(
+                "Operating point {} is not in the stream, which has {}, using 0",
+                self.settings.operating_point,
+                count
+            );
+            0
+        };
+        let all_layers = if self.settings.output_all_layers {
+            1
+        } else {
+            0
+        };
+
+        Ok((operating_point | all_layers << 10) as _)
+    }
+}
+
+impl ParseState {
+    /// The current decoder, created by the first sequence header.
+    fn raw_decoder(&self) -> Result<ffi::cuvid::CUvideodecoder, DecoderError> {
+        self.decoder
+            .as_ref()
+            .map(|decoder| decoder.inner)
+            .ok_or(DecoderError::NotInitialized)
+    }
+
+    /// Output format matching the stream, as selected by NvDecoder.cpp in the Video Codec SDK samples.
+    fn auto_output_format(&self, decode_caps: &DecoderCaps) -> Option<VideoSurfaceFormat> {
+        let high_bit_depth = self.bit_depth_minus8 != 0;
+        let preferred = match self.chroma_format {
+            VideoChromaFormat::YUV444 if high_bit_depth => VideoSurfaceFormat::YUV444_16,
+            VideoChromaFormat::YUV444 => VideoSurfaceFormat::YUV444,
+            VideoChromaFormat::YUV422 => VideoSurfaceFormat::NV12,
+            _ if high_bit_depth => VideoSurfaceFormat::P016,
+            _ => VideoSurfaceFormat::NV12,
+        };
+
+        // Check if output format supported. If not, check falback options
+        [
+            preferred,
+            VideoSurfaceFormat::NV12,
+            VideoSurfaceFormat::P016,
+            VideoSurfaceFormat::YUV444,
+            VideoSurfaceFormat::YUV444_16,
+        ]
+        .iter()
+        .cloned()
+        .find(|&format| decode_caps.supports_output_format(format))
     }
+}
+
+/// Whether the new sequence header differs in anything the decoder was created for.
+fn sequence_changed(
+    previous: &ffi::cuvid::CUVIDEOFORMAT,
+    current: &ffi::cuvid::CUVIDEOFORMAT,
+) -> bool {
+    previous.codec != current.codec
+        || previous.coded_width != current.coded_width
+        || previous.coded_height != current.coded_height
+        || previous.chroma_format != current.chroma_format
+        || previous.bit_depth_luma_minus8 != current.bit_depth_luma_minus8
+        || previous.bit_depth_chroma_minus8 != current.bit_depth_chroma_minus8
+        || previous.progressive_sequence != current.progressive_sequence
+        || previous.display_area.left != current.display_area.left
+        || previous.display_area.top != current.display_area.top
+        || previous.display_area.right != current.display_area.right
+        || previous.display_area.bottom != current.display_area.bottom
+}
 
-    fn operating_point_cb(&self, _op_info: *mut ffi::cuvid::CUVIDOPERATINGPOINTINFO) -> i32 {
-        0
+/// Item of `FramesIter`.
+pub enum DecodeEvent<'a> {
+    Frame(GpuFrame<'a>),
+    /// The stream switched to a new sequence, the frames that follow have this format.
+    SequenceChanged(VideoFormat),
+}
+
+impl<'a> DecodeEvent<'a> {
+    pub fn into_frame(self) -> Option<GpuFrame<'a>> {
+        match self {
+            DecodeEvent::Frame(frame) => Some(frame),
+            DecodeEvent::SequenceChanged(_) => None,
+        }
     }
 }
 
-pub struct FramesIter<'a, 'b> {
-    inner: &'a Inner,
-    context: Option<&'b super::cuda::context::CuContext>,
+pub struct FramesIter<'a, 'b: 'a> {
+    inner: &'a Inner<'a>,
+    context: Option<&'b CuContext<'b>>,
 }
 
-impl<'a, 'b> Iterator for FramesIter<'a, 'b> {
-    type Item = GpuFrame;
+impl<'a, 'b: 'a> Iterator for FramesIter<'a, 'b> {
+    type Item = Result<DecodeEvent<'a>, DecoderError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut frame = self.inner.receiver.recv().ok()?;
+        loop {
+            let pending = self.inner.lock_pending().pop_front();
+            if let Some((frame, status)) = pending {
+                return Some(self.inner.map_frame(frame, status, self.context));
+            }
+            let message = match self.inner.receiver.recv() {
+                Ok(Message::EndOfStream) | Err(_) => return None,
+                Ok(message) => message,
+            };
+            if let Some(event) = self.inner.event(message, self.context) {
+                return Some(event);
+            }
+        }
+    }
+}
+
+/// Asynchronous `FramesIter`, from `Decoder::frames_stream`.
+pub struct FramesStream<'a, 'b: 'a> {
+    inner: &'a Inner<'a>,
+    context: Option<&'b CuContext<'b>>,
+    messages: flume::async::RecvStream<'a, Message>,
+}
+
+impl<'a, 'b: 'a> Stream for FramesStream<'a, 'b> {
+    type Item = Result<DecodeEvent<'a>, DecoderError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            let pending = self.inner.lock_pending().pop_front();
+            if let Some((frame, status)) = pending {
+                return Poll::Ready(Some(self.inner.map_frame(frame, status, self.context)));
+            }
+            let message = match Pin::new(&mut self.messages).poll_next(cx) {
+                Poll::Ready(Some(Message::EndOfStream)) | Poll::Ready(None) => {
+                    return Poll::Ready(None)
+                }
+                Poll::Ready(Some(message)) => message,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = self.inner.event(message, self.context) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+impl<'a> Inner<'a> {
+    /// Turns a message from the parser callbacks into an event, `None` for dropped frames.
+    fn event(
+        &'a self,
+        message: Message,
+        context: Option<&'a CuContext<'a>>,
+    ) -> Option<Result<DecodeEvent<'a>, DecoderError>> {
+        let frame = match message {
+            Message::Frame(frame) => frame,
+            Message::SequenceChanged(format) => {
+                return Some(Ok(DecodeEvent::SequenceChanged(format)));
+            }
+            Message::Error(err) => return Some(Err(err)),
+            Message::EndOfStream => return None,
+        };
+
+        let mut decode_status: ffi::cuvid::CUVIDGETDECODESTATUS = unsafe { std::mem::zeroed() };
+        let status = unsafe {
+            self.nvcuvid
+                .cuvidGetDecodeStatus(frame.decoder.inner, frame.index, &mut decode_status)
+        }
+        .result(decode_status.decodeStatus)
+        .ok()
+        .and_then(DecodeStatus::from_raw);
+
+        if status.map(DecodeStatus::is_corrupted) == Some(true) {
+            self.corrupted_frames.fetch_add(1, Ordering::Relaxed);
+            if self.settings.drop_corrupted {
+                tracing::debug!("Dropping corrupted frame {}", frame.timestamp());
+                return None;
+            }
+            tracing::warn!("Frame {} decoded with errors", frame.timestamp());
+        }
+
+        Some(self.map_frame(frame, status, context))
+    }
+
+    /// Maps a displayed frame in `context`, or the decoder's context.
+    ///
+    /// The frame is kept for the next call if all the output surfaces are mapped.
+    fn map_frame(
+        &'a self,
+        mut frame: Box<PreparedFrame>,
+        status: Option<DecodeStatus>,
+        context: Option<&'a CuContext<'a>>,
+    ) -> Result<DecodeEvent<'a>, DecoderError> {
+        let nvcuvid = self.nvcuvid;
+        if !self.reserve_mapping() {
+            self.lock_pending().push_front((frame, status));
+            return Err(DecoderError::AllSurfacesMapped(
+                self.settings.output_surfaces,
+            ));
+        }
 
         let mut dp_src_frame: CUdeviceptr = 0;
         let mut n_src_pitch = 0u32;
+        // the driver writes the address of the histogram here
+        let mut histogram_ptr: CUdeviceptr = 0;
+        if frame.histogram.is_some() {
+            frame.parameters.histogram_dptr = &mut histogram_ptr;
+        }
+
+        let context = context.unwrap_or(&self.context);
 
         unsafe {
-            if !ffi::cuda::cuCtxPushCurrent_v2(
-                self.context
-                    .map(|c| c.context)
-                    .unwrap_or(self.inner.context.context),
-            )
-            .ok()
-            {
-                tracing::error!("Failed to push current context.");
-                return None;
+            if let Err(err) = nvcuvid.cuda.cuCtxPushCurrent_v2(context.inner).err() {
+                self.mapped_frames.fetch_sub(1, Ordering::AcqRel);
+                return Err(DecoderError::Cuda(err));
             }
-            // tracing::info!("{}: {}", context.is_some(), frame.index);
-            if let Err(err) = ffi::cuvid::cuvidMapVideoFrame64(
-                self.inner.decoder,
+            let res = nvcuvid.cuvidMapVideoFrame64(
+                frame.decoder.inner,
                 frame.index,
                 &mut dp_src_frame,
                 &mut n_src_pitch,
                 &mut frame.parameters,
-            )
-            .err()
-            {
-                tracing::error!("Failed to map video frame: {}", err);
-                return None;
+            );
+            if !nvcuvid.cuda.cuCtxPopCurrent_v2(std::ptr::null_mut()).ok() {
+                tracing::error!("Failed to pop current context.");
             }
+            if let Err(err) = res.err() {
+                self.mapped_frames.fetch_sub(1, Ordering::AcqRel);
+                return Err(DecoderError::Cuda(err));
+            }
+        }
 
-            let mut decode_status: ffi::cuvid::CUVIDGETDECODESTATUS = std::mem::zeroed();
-
-            if ffi::cuvid::cuvidGetDecodeStatus(self.inner.decoder, frame.index, &mut decode_status)
-                .ok()
-            {
-                if decode_status.decodeStatus
-                    == ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Error
-                    || decode_status.decodeStatus
-                        == ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Error_Concealed
-                {
-                    tracing::error!("Decoding error occured");
-                }
+        let (mut ptr, mut pitch) = (dp_src_frame, n_src_pitch);
+        let (mut height, mut surface_height) = (frame.size.1, frame.surface_height);
+        if let (FieldOutput::SeparateFields, Some(field)) =
+            (self.settings.field_output, frame.field)
+        {
+            // every other row of the woven frame, the bottom field starting one row down
+            if field == Field::Bottom {
+                ptr += pitch as CUdeviceptr;
             }
+            height = (height + (field == Field::Top) as u32) / 2;
+            pitch *= 2;
+            surface_height /= 2;
         }
 
         let frame = GpuFrame {
-            width: self.inner.out_size.0,
-            height: self.inner.out_size.1,
-            ptr: dp_src_frame,
-            pitch: n_src_pitch,
+            nvcuvid,
+            width: frame.size.0,
+            height,
+            ptr,
+            pitch,
             timestamp: frame.timestamp(),
-            decoder: self.inner.decoder,
+            format: frame.format,
+            status,
+            film_grain: frame.film_grain,
+            sei: std::mem::take(&mut frame.sei),
+            field: frame.field,
+            decoder: frame.decoder.inner,
+            _owner: Some(frame.decoder.clone()),
+            mapped_ptr: dp_src_frame,
+            histogram: frame.histogram.map(|layout| (histogram_ptr, layout)),
+            surface_height,
+            mapped_frames: &self.mapped_frames,
+            context,
         };
 
-        Some(frame)
+        Ok(DecodeEvent::Frame(frame))
     }
 }
 
@@ -561,38 +1687,594 @@ pub unsafe extern "C" fn handle_video_sequence_proc(
     user_data: *mut std::os::raw::c_void,
     video_format: *mut ffi::cuvid::CUVIDEOFORMAT,
 ) -> i32 {
-    let decoder = user_data as *mut Inner;
-    let decoder = &mut *decoder;
+    let decoder = &*(user_data as *const Inner);
 
-    decoder.sequence_cb(video_format)
+    decoder.callback(0, |decoder, state| decoder.sequence_cb(state, video_format))
 }
 
 pub unsafe extern "C" fn handle_picture_decode_proc(
     user_data: *mut std::os::raw::c_void,
     pic_params: *mut ffi::cuvid::CUVIDPICPARAMS,
 ) -> i32 {
-    let decoder = user_data as *mut Inner;
-    let decoder = &*decoder;
+    let decoder = &*(user_data as *const Inner);
 
-    decoder.picture_decode_cb(pic_params)
+    decoder.callback(0, |decoder, state| {
+        decoder.picture_decode_cb(state, pic_params)
+    })
 }
 
 pub unsafe extern "C" fn handle_picture_display_proc(
     user_data: *mut std::os::raw::c_void,
     display_info: *mut ffi::cuvid::CUVIDPARSERDISPINFO,
 ) -> i32 {
-    let decoder = user_data as *mut Inner;
-    let decoder = &mut *decoder;
+    let decoder = &*(user_data as *const Inner);
 
-    decoder.picture_display_cb(display_info)
+    decoder.callback(0, |decoder, state| {
+        decoder.picture_display_cb(state, display_info)
+    })
 }
 
 pub unsafe extern "C" fn handle_operating_point_proc(
     user_data: *mut std::os::raw::c_void,
     op_info: *mut ffi::cuvid::CUVIDOPERATINGPOINTINFO,
 ) -> i32 {
-    let decoder = user_data as *mut Inner;
-    let decoder = &*decoder;
+    let decoder = &*(user_data as *const Inner);
+
+    // 0 selects operating point 0, failures are negative
+    decoder.callback(-1, |decoder, _| decoder.operating_point_cb(op_info))
+}
+
+pub unsafe extern "C" fn handle_sei_msg_proc(
+    user_data: *mut std::os::raw::c_void,
+    sei_info: *mut ffi::cuvid::CUVIDSEIMESSAGEINFO,
+) -> i32 {
+    let decoder = &*(user_data as *const Inner);
+
+    decoder.callback(0, |decoder, state| decoder.sei_msg_cb(state, sei_info))
+}
 
-    decoder.operating_point_cb(op_info)
+#[cfg(test)]
+mod test {
+    use crate::cuda::test::{context, cuda};
+    use crate::cuda::Cuda;
+    use crate::encode::test::{upload_flat, with_encoder};
+    use crate::encode::BufferFormat;
+    use tracing_test::traced_test;
+
+    use super::{
+        Codec, Cuvid, DecodeEvent, DecodeStatus, Decoder, DecoderError, GpuFrame, Packet, Rect,
+        VideoChromaFormat, VideoSurfaceFormat,
+    };
+    use futures_core::Stream;
+    use std::convert::TryFrom;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use std::thread::Thread;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls `poll` on this thread until it is ready, parking in between.
+    fn block_on<T, F: FnMut(&mut Context) -> Poll<T>>(mut poll: F) -> T {
+        let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(value) = poll(&mut cx) {
+                return value;
+            }
+            std::thread::park();
+        }
+    }
+
+    /// Encodes `count` flat pictures of `width`x`height` to an H.264 elementary stream, one packet per picture.
+    pub(crate) fn encode_h264(cuda: &Cuda, width: u32, height: u32, count: u64) -> Vec<Vec<u8>> {
+        encode_stream(cuda, crate::encode::Codec::H264, 1, 1, width, height, count)
+            .into_iter()
+            .map(|packet| packet.data)
+            .collect()
+    }
+
+    /// Like `encode_h264`, for any codec, with `layers` temporal layers and `frame_interval_p`
+    /// pictures between reference pictures, so B-frames above 1. Packets are in decode order.
+    fn encode_stream(
+        cuda: &Cuda,
+        codec: crate::encode::Codec,
+        layers: u32,
+        frame_interval_p: u32,
+        width: u32,
+        height: u32,
+        count: u64,
+    ) -> Vec<crate::encode::Packet> {
+        with_encoder(cuda, |encoder| {
+            // low latency tuning disables B-frames
+            let tuning = if frame_interval_p > 1 {
+                ffi::encode_api::NV_ENC_TUNING_INFO_NV_ENC_TUNING_INFO_HIGH_QUALITY
+            } else {
+                ffi::encode_api::NV_ENC_TUNING_INFO_NV_ENC_TUNING_INFO_LOW_LATENCY
+            };
+            let mut config = encoder
+                .preset_config(
+                    codec,
+                    ffi::constants::encode_api::NV_ENC_PRESET_P4_GUID,
+                    tuning,
+                )
+                .unwrap();
+            config.as_raw_mut().frameIntervalP = frame_interval_p as i32;
+            if layers > 1 {
+                config.enable_temporal_svc(layers).unwrap();
+            }
+            encoder
+                .initialize_with_config(&mut config, width, height, (30, 1))
+                .unwrap();
+
+            let pool = encoder
+                .input_buffer_pool(width, height, BufferFormat::NV12, 1)
+                .unwrap();
+            let input = pool.acquire().unwrap();
+            upload_flat(encoder, &input, 64);
+
+            // B-frames hold back the pictures they reference, one buffer per picture keeps
+            // every pending output apart
+            let outputs = (0..count)
+                .map(|_| encoder.bitstream_buffer().unwrap())
+                .collect::<Vec<_>>();
+            let mut packets = Vec::new();
+            for (timestamp, output) in outputs.iter().enumerate() {
+                if encoder
+                    .encode_picture(&input, None, output, timestamp as u64)
+                    .unwrap()
+                {
+                    for output in &outputs[packets.len()..=timestamp] {
+                        packets.push(encoder.lock_bitstream(output).unwrap());
+                    }
+                }
+            }
+            encoder.end_of_stream().unwrap();
+            for output in &outputs[packets.len()..] {
+                packets.push(encoder.lock_bitstream(output).unwrap());
+            }
+
+            packets
+        })
+    }
+
+    /// Initializes CUDA and encodes `count` flat H.264 pictures of `width`x`height`.
+    pub(crate) fn h264_stream(width: u32, height: u32, count: u64) -> (Cuda, Vec<Vec<u8>>) {
+        let cuda = cuda();
+        let packets = encode_h264(&cuda, width, height, count);
+
+        (cuda, packets)
+    }
+
+    /// Queues `packets` with timestamps 0, 1, ... and the end of stream, keeping what `f`
+    /// returns for each decoded frame.
+    pub(crate) fn decode_packets<'d, T, F>(
+        decoder: &'d Decoder,
+        packets: &[Vec<u8>],
+        f: F,
+    ) -> Vec<T>
+    where
+        F: FnMut(GpuFrame<'d>) -> T,
+    {
+        for (timestamp, packet) in packets.iter().enumerate() {
+            decoder.queue(packet, timestamp as i64).unwrap();
+        }
+        decoder.send_eos().unwrap();
+
+        decoder
+            .frames(None)
+            .map(Result::unwrap)
+            .filter_map(DecodeEvent::into_frame)
+            .map(f)
+            .collect()
+    }
+
+    #[test]
+    #[traced_test]
+    fn decode_h264() {
+        let (cuda, packets) = h264_stream(256, 144, 4);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder(Codec::H264, false, true, (0, 0), None, None)
+            .unwrap();
+
+        let frames = decode_packets(&decoder, &packets, |frame| {
+            assert_eq!(frame.status, Some(DecodeStatus::Success));
+            (frame.width, frame.height, frame.timestamp)
+        });
+        assert_eq!(
+            frames,
+            vec![(256, 144, 0), (256, 144, 1), (256, 144, 2), (256, 144, 3)]
+        );
+
+        let format = decoder.video_format().unwrap();
+        assert_eq!(format.codec, Codec::H264);
+        assert_eq!(format.display_area, Rect::new(0, 0, 256, 144));
+        assert_eq!(format.output_size, (256, 144));
+        assert!(format.progressive);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decode_h264_async() {
+        let (cuda, packets) = h264_stream(256, 144, 4);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder(Codec::H264, false, true, (0, 0), None, None)
+            .unwrap();
+        let queued = packets
+            .into_iter()
+            .enumerate()
+            .map(|(timestamp, packet)| {
+                decoder.queue_async(Packet::owned(packet).timestamp(timestamp as i64))
+            })
+            .collect::<Vec<_>>();
+        for mut queued in queued {
+            block_on(|cx| Pin::new(&mut queued).poll(cx)).unwrap();
+        }
+        decoder.send_eos().unwrap();
+
+        let mut stream = decoder.frames_stream(None);
+        let mut timestamps = Vec::new();
+        while let Some(event) = block_on(|cx| Pin::new(&mut stream).poll_next(cx)) {
+            if let Some(frame) = event.unwrap().into_frame() {
+                timestamps.push(frame.timestamp);
+            }
+        }
+        assert_eq!(timestamps, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_flush() {
+        let (cuda, packets) = h264_stream(256, 144, 4);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let mut decoder = cuvid
+            .decoder(Codec::H264, false, true, (0, 0), None, None)
+            .unwrap();
+        for (timestamp, packet) in packets.iter().enumerate() {
+            decoder.queue(packet, timestamp as i64).unwrap();
+        }
+        decoder.flush().unwrap();
+
+        // seek back to the start, then decode to the end twice
+        for round in 1..3 {
+            let base = round * 10;
+            decoder.queue_discontinuity(&packets[0], base).unwrap();
+            for (timestamp, packet) in packets.iter().enumerate().skip(1) {
+                decoder.queue(packet, base + timestamp as i64).unwrap();
+            }
+            decoder.send_eos().unwrap();
+
+            let timestamps = decoder
+                .frames(None)
+                .map(Result::unwrap)
+                .filter_map(DecodeEvent::into_frame)
+                .map(|frame| frame.timestamp)
+                .collect::<Vec<_>>();
+            assert_eq!(timestamps, vec![base, base + 1, base + 2, base + 3]);
+        }
+    }
+
+    /// A flat grey baseline JPEG with luma sampled `sampling` times the chroma.
+    fn flat_jpeg(width: u16, height: u16, sampling: (u8, u8)) -> Vec<u8> {
+        let mut jpeg = vec![0xff, 0xd8];
+        // quantization table 0, all ones
+        jpeg.extend_from_slice(&[0xff, 0xdb, 0, 67, 0]);
+        jpeg.extend_from_slice(&[1; 64]);
+        // 8-bit frame with 3 components, the chroma ones sampled once per MCU
+        jpeg.extend_from_slice(&[0xff, 0xc0, 0, 17, 8]);
+        jpeg.extend_from_slice(&height.to_be_bytes());
+        jpeg.extend_from_slice(&width.to_be_bytes());
+        jpeg.extend_from_slice(&[3, 1, sampling.0 << 4 | sampling.1, 0]);
+        jpeg.extend_from_slice(&[2, 0x11, 0, 3, 0x11, 0]);
+        // DC and AC tables 0, each with a single 1-bit code: difference 0 and end of block
+        for &class in &[0x00, 0x10] {
+            jpeg.extend_from_slice(&[0xff, 0xc4, 0, 20, class, 1]);
+            jpeg.extend_from_slice(&[0; 16]);
+        }
+        jpeg.extend_from_slice(&[0xff, 0xda, 0, 12, 3, 1, 0, 2, 0, 3, 0, 0, 63, 0]);
+        // two 0 bits per block, padded with 1 bits
+        let mcu = (8 * sampling.0 as u32, 8 * sampling.1 as u32);
+        let mcus = (width as u32).div_ceil(mcu.0) * (height as u32).div_ceil(mcu.1);
+        let bits = mcus * (sampling.0 * sampling.1 + 2) as u32 * 2;
+        let len = jpeg.len();
+        jpeg.resize(len + bits.div_ceil(8) as usize, 0);
+        *jpeg.last_mut().unwrap() |= ((1u32 << ((8 - bits % 8) % 8)) - 1) as u8;
+        jpeg.extend_from_slice(&[0xff, 0xd9]);
+
+        jpeg
+    }
+
+    #[test]
+    #[traced_test]
+    fn decode_mjpeg() {
+        let cuda = cuda();
+        let cuvid = Cuvid::new(&cuda).unwrap();
+
+        // 4:4:4 keeps its chroma, 4:2:2 is downsampled to NV12 like 4:2:0
+        for &(sampling, chroma_format, output_format) in &[
+            ((2, 2), VideoChromaFormat::YUV420, VideoSurfaceFormat::NV12),
+            ((2, 1), VideoChromaFormat::YUV422, VideoSurfaceFormat::NV12),
+            (
+                (1, 1),
+                VideoChromaFormat::YUV444,
+                VideoSurfaceFormat::YUV444,
+            ),
+        ] {
+            let decoder = cuvid.decoder_builder(Codec::JPEG).build().unwrap();
+            let jpeg = flat_jpeg(64, 48, sampling);
+
+            let frames = decode_packets(&decoder, &[jpeg.clone(), jpeg], |frame| {
+                assert_eq!(frame.format, output_format);
+                let host = frame.download().unwrap();
+                // grey, so luma and chroma all sit at mid scale
+                for plane in host.planes() {
+                    assert!(plane.iter().all(|&sample| (sample as i32 - 128).abs() <= 2));
+                }
+                (frame.width, frame.height, frame.timestamp)
+            });
+            let format = decoder.video_format().unwrap();
+            assert_eq!(format.codec, Codec::JPEG);
+            assert_eq!(format.chroma_format, chroma_format);
+            assert_eq!(frames, vec![(64, 48, 0), (64, 48, 1)]);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_histogram() {
+        let (cuda, packets) = h264_stream(256, 144, 2);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let ctx = context(&cuda);
+        let caps = cuvid
+            .decoder_caps(&ctx, Codec::H264, VideoChromaFormat::YUV420, 0)
+            .unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .histogram(true)
+            .build()
+            .unwrap();
+        for (timestamp, packet) in packets.iter().enumerate() {
+            let res = decoder.queue(packet, timestamp as i64);
+            if !caps.histogram_supported {
+                assert_eq!(res, Err(DecoderError::HistogramUnsupported(Codec::H264)));
+                return;
+            }
+            res.unwrap();
+        }
+        decoder.send_eos().unwrap();
+
+        for frame in decoder
+            .frames(None)
+            .map(Result::unwrap)
+            .filter_map(DecodeEvent::into_frame)
+        {
+            let histogram = frame.histogram().unwrap().unwrap();
+            assert_eq!(histogram.len(), caps.histogram_max_bins as usize);
+            // the pictures are flat, so every sample falls in the same bin
+            assert_eq!(histogram.iter().filter(|&&count| count > 0).count(), 1);
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_caps() {
+        let cuda = cuda();
+        let ctx = context(&cuda);
+
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let caps = cuvid
+            .decoder_caps(&ctx, Codec::H264, VideoChromaFormat::YUV420, 0)
+            .unwrap();
+        assert!(caps.supported);
+        assert!(caps.supports_output_format(VideoSurfaceFormat::NV12));
+        assert!(caps.output_formats().contains(&VideoSurfaceFormat::NV12));
+        assert!(caps.supports_size(1920, 1080));
+        assert!(!caps.supports_size(caps.max_size.0 + 16, caps.max_size.1));
+    }
+
+    #[test]
+    #[traced_test]
+    fn decode_av1_all_layers() {
+        let cuda = cuda();
+        let packets = encode_stream(&cuda, crate::encode::Codec::AV1, 2, 1, 256, 144, 4)
+            .into_iter()
+            .map(|packet| packet.data)
+            .collect::<Vec<_>>();
+
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::AV1)
+            .operating_point(0)
+            .output_all_layers(true)
+            .build()
+            .unwrap();
+
+        let film_grain = decode_packets(&decoder, &packets, |frame| frame.film_grain);
+        assert_eq!(film_grain, vec![false; 4]);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decode_b_frames() {
+        let cuda = cuda();
+        let packets = encode_stream(&cuda, crate::encode::Codec::H264, 1, 3, 256, 144, 7);
+        // the B-frames follow the pictures they reference
+        assert!(packets
+            .windows(2)
+            .any(|pair| pair[1].timestamp < pair[0].timestamp));
+
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid.decoder_builder(Codec::H264).build().unwrap();
+        for packet in &packets {
+            decoder
+                .queue(&packet.data, packet.timestamp as i64)
+                .unwrap();
+        }
+        decoder.send_eos().unwrap();
+
+        let timestamps = decoder
+            .frames(None)
+            .map(Result::unwrap)
+            .filter_map(DecodeEvent::into_frame)
+            .map(|frame| frame.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, (0..7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_download() {
+        let (cuda, packets) = h264_stream(256, 144, 1);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid.decoder_builder(Codec::H264).build().unwrap();
+        decoder.queue(&packets[0], 0).unwrap();
+        decoder.send_eos().unwrap();
+
+        // map in our own context so the stream belongs to it
+        let ctx = context(&cuda);
+        let stream = ctx.new_stream(true).unwrap();
+        let frame = decoder
+            .frames(Some(&ctx))
+            .map(Result::unwrap)
+            .filter_map(DecodeEvent::into_frame)
+            .next()
+            .unwrap();
+
+        let host = frame.download().unwrap();
+        assert_eq!(host.data.len(), 256 * 144 * 3 / 2);
+        let planes = host.planes();
+        assert!(planes[0].iter().all(|&y| (y as i32 - 64).abs() <= 2));
+        assert!(planes[1].iter().all(|&c| (c as i32 - 128).abs() <= 2));
+
+        let pitch = 512;
+        let mut padded = vec![0u8; pitch * 216];
+        frame
+            .download_into(&mut padded, pitch, Some(&stream))
+            .unwrap();
+        for (row, packed) in padded.chunks(pitch).zip(host.data.chunks(256)) {
+            assert_eq!(&row[..256], packed);
+        }
+        assert_eq!(
+            frame.download_into(&mut padded, 128, None),
+            Err(DecoderError::PitchTooSmall {
+                pitch: 128,
+                min_pitch: 256
+            })
+        );
+    }
+
+    /// Decodes 2 frames at 256x144 then 2 at 320x180, with the first ones still queued when
+    /// the size changes unless `drain_first`.
+    fn decode_resolution_change(max_size: (u32, u32), drain_first: bool) {
+        let (cuda, first) = h264_stream(256, 144, 2);
+        let second = encode_h264(&cuda, 320, 180, 2);
+
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .max_size(max_size.0, max_size.1)
+            .build()
+            .unwrap();
+        let drain = |decoder: &super::Decoder| {
+            decoder
+                .frames(None)
+                .map(|event| match event.unwrap() {
+                    DecodeEvent::Frame(frame) => Some((frame.width, frame.height)),
+                    DecodeEvent::SequenceChanged(format) => {
+                        assert_eq!(format.output_size, (320, 180));
+                        assert_eq!(format.display_area, Rect::new(0, 0, 320, 180));
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut events = Vec::new();
+        for (timestamp, packet) in first.iter().chain(&second).enumerate() {
+            if drain_first && timestamp == first.len() {
+                decoder.send_eos().unwrap();
+                events.extend(drain(&decoder));
+            }
+            decoder.queue(packet, timestamp as i64).unwrap();
+        }
+        decoder.send_eos().unwrap();
+        events.extend(drain(&decoder));
+        assert_eq!(
+            events,
+            vec![
+                Some((256, 144)),
+                Some((256, 144)),
+                None,
+                Some((320, 180)),
+                Some((320, 180))
+            ]
+        );
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_reconfigure() {
+        decode_resolution_change((320, 192), true);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_recreate() {
+        decode_resolution_change((0, 0), true);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_change_with_queued_frames() {
+        // the queued frames keep their decoder instead of being resized by a reconfigure
+        decode_resolution_change((320, 192), false);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_drop_corrupted() {
+        let (cuda, mut packets) = h264_stream(256, 144, 4);
+        // damage the slice data of the second picture
+        let len = packets[1].len();
+        for byte in &mut packets[1][len / 2..] {
+            *byte = 0xff;
+        }
+
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .drop_corrupted(true)
+            .build()
+            .unwrap();
+
+        let statuses = decode_packets(&decoder, &packets, |frame| frame.status.unwrap());
+        assert!(statuses.iter().all(|status| !status.is_corrupted()));
+        assert_eq!(statuses.len() as u64 + decoder.corrupted_frames(), 4);
+    }
+
+    #[test]
+    fn unknown_driver_values() {
+        assert_eq!(Codec::try_from(1000), Err(DecoderError::UnknownCodec(1000)));
+        assert_eq!(
+            VideoChromaFormat::try_from(1000),
+            Err(DecoderError::UnknownChromaFormat(1000))
+        );
+        assert_eq!(
+            VideoSurfaceFormat::try_from(1000),
+            Err(DecoderError::UnknownSurfaceFormat(1000))
+        );
+        assert_eq!(
+            Codec::try_from(ffi::cuvid::cudaVideoCodec_enum_cudaVideoCodec_H264),
+            Ok(Codec::H264)
+        );
+    }
 }
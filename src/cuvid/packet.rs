@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+
+use super::ffi;
+
+/// A chunk of bitstream for `Decoder::queue_packet` and the flags the parser reads it with.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Packet<'a> {
+    data: Cow<'a, [u8]>,
+    timestamp: Option<i64>,
+    end_of_picture: bool,
+    discontinuity: bool,
+    end_of_stream: bool,
+}
+
+impl Packet<'static> {
+    /// Like `new`, owning `data`, for `Decoder::queue_async`.
+    pub fn owned(data: Vec<u8>) -> Self {
+        Self {
+            data: Cow::Owned(data),
+            ..Default::default()
+        }
+    }
+}
+
+impl<'a> Packet<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data: Cow::Borrowed(data),
+            ..Default::default()
+        }
+    }
+
+    /// Presentation timestamp of the picture starting in `data`, handed back on its `GpuFrame`.
+    ///
+    /// It is in units of the decoder's clock rate, frames are output in presentation order.
+    pub fn timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// `data` completes a picture, so it is decoded right away instead of when the next one
+    /// starts.
+    pub fn end_of_picture(mut self, end_of_picture: bool) -> Self {
+        self.end_of_picture = end_of_picture;
+        self
+    }
+
+    /// `data` does not follow the previous packet, as after a seek or a transmission loss.
+    pub fn discontinuity(mut self, discontinuity: bool) -> Self {
+        self.discontinuity = discontinuity;
+        self
+    }
+
+    /// `data` is the last packet, every picture queued is displayed and `frames` ends after them.
+    pub fn end_of_stream(mut self, end_of_stream: bool) -> Self {
+        self.end_of_stream = end_of_stream;
+        self
+    }
+
+    pub(crate) fn flags(&self) -> ffi::cuvid::CUvideopacketflags {
+        let mut flags = 0;
+        if self.timestamp.is_some() {
+            flags |= ffi::cuvid::CUvideopacketflags_CUVID_PKT_TIMESTAMP;
+        }
+        if self.end_of_picture {
+            flags |= ffi::cuvid::CUvideopacketflags_CUVID_PKT_ENDOFPICTURE;
+        }
+        if self.discontinuity {
+            flags |= ffi::cuvid::CUvideopacketflags_CUVID_PKT_DISCONTINUITY;
+        }
+        if self.end_of_stream {
+            // the display callback is only told about the end of stream when asked to
+            flags |= ffi::cuvid::CUvideopacketflags_CUVID_PKT_ENDOFSTREAM
+                | ffi::cuvid::CUvideopacketflags_CUVID_PKT_NOTIFY_EOS;
+        }
+
+        flags
+    }
+
+    /// The packet to pass to `cuvidParseVideoData`, borrowing `data`.
+    pub(crate) fn raw(&self) -> ffi::cuvid::CUVIDSOURCEDATAPACKET {
+        ffi::cuvid::CUVIDSOURCEDATAPACKET {
+            flags: self.flags() as _,
+            payload_size: self.data.len() as _,
+            payload: self.data.as_ptr(),
+            timestamp: self.timestamp.unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ffi, Packet};
+
+    #[test]
+    fn packet_flags() {
+        let data = [0, 0, 1];
+        let packet = Packet::new(&data).timestamp(42).end_of_picture(true);
+        assert_eq!(
+            packet.flags(),
+            ffi::cuvid::CUvideopacketflags_CUVID_PKT_TIMESTAMP
+                | ffi::cuvid::CUvideopacketflags_CUVID_PKT_ENDOFPICTURE
+        );
+        let raw = packet.raw();
+        assert_eq!(raw.payload_size, 3);
+        assert_eq!(raw.timestamp, 42);
+        assert_eq!(
+            Packet::owned(data.to_vec())
+                .timestamp(42)
+                .end_of_picture(true),
+            packet
+        );
+
+        assert_eq!(
+            Packet::new(&[]).end_of_stream(true).flags(),
+            ffi::cuvid::CUvideopacketflags_CUVID_PKT_ENDOFSTREAM
+                | ffi::cuvid::CUvideopacketflags_CUVID_PKT_NOTIFY_EOS
+        );
+        assert_eq!(Packet::new(&data).raw().flags, 0);
+    }
+}
@@ -0,0 +1,144 @@
+/// A rectangle in pixels, `right` and `bottom` excluded.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+    pub left: i16,
+    pub top: i16,
+    pub right: i16,
+    pub bottom: i16,
+}
+
+impl Rect {
+    pub fn new(left: i16, top: i16, right: i16, bottom: i16) -> Self {
+        Self {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        (self.right - self.left).max(0) as _
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.bottom - self.top).max(0) as _
+    }
+}
+
+/// How the source area is scaled to the requested output size.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScaleMode {
+    /// Fills the output size, ignoring the aspect ratio.
+    Stretch,
+    /// Keeps the aspect ratio, shrinking the output to fit within the requested size.
+    Fit,
+    /// Keeps the aspect ratio and the requested size, centering the picture between bars.
+    ///
+    /// The decoder does not clear the bars, they hold whatever the surface held before.
+    Letterbox,
+}
+
+/// Where the decoder scaler reads from and writes to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Geometry {
+    pub(crate) target_size: (u32, u32),
+    pub(crate) display_area: Rect,
+    pub(crate) target_rect: Option<Rect>,
+}
+
+impl Geometry {
+    /// Scales `area` to `size` with `mode`, an explicit `target_rect` takes precedence over
+    /// letterboxing.
+    pub(crate) fn new(
+        area: Rect,
+        size: (u32, u32),
+        mode: ScaleMode,
+        target_rect: Option<Rect>,
+    ) -> Self {
+        let target_size = match mode {
+            ScaleMode::Stretch | ScaleMode::Letterbox => size,
+            ScaleMode::Fit => fit(area, size),
+        };
+        let target_rect = match (target_rect, mode) {
+            (Some(rect), _) => Some(rect),
+            (None, ScaleMode::Letterbox) => {
+                let (width, height) = fit(area, size);
+                let left = ((size.0 - width) / 2) & !1;
+                let top = ((size.1 - height) / 2) & !1;
+                Some(Rect::new(
+                    left as _,
+                    top as _,
+                    (left + width) as _,
+                    (top + height) as _,
+                ))
+            }
+            (None, _) => None,
+        };
+
+        Self {
+            target_size,
+            display_area: area,
+            target_rect,
+        }
+    }
+}
+
+/// Largest even size within `size` with the aspect ratio of `area`.
+fn fit(area: Rect, size: (u32, u32)) -> (u32, u32) {
+    let (width, height) = (area.width() as u64, area.height() as u64);
+    if width == 0 || height == 0 {
+        return size;
+    }
+    let (max_width, max_height) = (size.0 as u64, size.1 as u64);
+    let (width, height) = if width * max_height > height * max_width {
+        (max_width, height * max_width / width)
+    } else {
+        (width * max_height / height, max_height)
+    };
+
+    (width as u32 & !1, height as u32 & !1)
+}
+
+#[cfg(test)]
+mod test {
+    use tracing_test::traced_test;
+
+    use super::super::test::{decode_packets, h264_stream};
+    use super::super::{Codec, Cuvid};
+    use super::{Geometry, Rect, ScaleMode};
+
+    #[test]
+    fn scale_geometry() {
+        let area = Rect::new(0, 0, 1920, 1080);
+
+        let fit = Geometry::new(area, (320, 320), ScaleMode::Fit, None);
+        assert_eq!(fit.target_size, (320, 180));
+        assert_eq!(fit.target_rect, None);
+
+        let letterbox = Geometry::new(area, (320, 320), ScaleMode::Letterbox, None);
+        assert_eq!(letterbox.target_size, (320, 320));
+        assert_eq!(letterbox.target_rect, Some(Rect::new(0, 70, 320, 250)));
+
+        let stretch = Geometry::new(area, (320, 320), ScaleMode::Stretch, None);
+        assert_eq!(stretch.target_size, (320, 320));
+        assert_eq!(stretch.target_rect, None);
+    }
+
+    #[test]
+    #[traced_test]
+    fn decoder_crop_and_fit() {
+        let (cuda, packets) = h264_stream(256, 144, 2);
+        let cuvid = Cuvid::new(&cuda).unwrap();
+        let decoder = cuvid
+            .decoder_builder(Codec::H264)
+            .crop(Rect::new(0, 0, 128, 144))
+            .output_size(64, 64)
+            .scale_mode(ScaleMode::Fit)
+            .build()
+            .unwrap();
+
+        let sizes = decode_packets(&decoder, &packets, |frame| (frame.width, frame.height));
+        assert_eq!(sizes, vec![(56, 64), (56, 64)]);
+    }
+}
@@ -0,0 +1,280 @@
+use super::ffi;
+use super::Codec;
+
+// H.264 and HEVC SEI payload types
+const SEI_PIC_TIMING: u8 = 1;
+const SEI_USER_DATA_REGISTERED: u8 = 4;
+const SEI_USER_DATA_UNREGISTERED: u8 = 5;
+const SEI_TIME_CODE: u8 = 136;
+const SEI_MASTERING_DISPLAY_COLOUR_VOLUME: u8 = 137;
+const SEI_CONTENT_LIGHT_LEVEL: u8 = 144;
+
+// AV1 metadata OBU types, which the parser reports in place of SEI payload types
+const AV1_METADATA_HDR_CLL: u8 = 1;
+const AV1_METADATA_HDR_MDCV: u8 = 2;
+const AV1_METADATA_ITUT_T35: u8 = 4;
+const AV1_METADATA_TIMECODE: u8 = 5;
+
+/// An SEI message, or AV1 metadata OBU, attached to a decoded picture.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SeiMessage {
+    /// ITU-T T.35 user data starting with the country code, carries A/53 closed captions.
+    UserDataRegistered(Vec<u8>),
+    UserDataUnregistered {
+        uuid: [u8; 16],
+        data: Vec<u8>,
+    },
+    MasteringDisplay(MasteringDisplay),
+    ContentLightLevel(ContentLightLevel),
+    /// Up to 3 clock timestamps, one per field or frame of the picture.
+    TimeCode(Vec<TimeCode>),
+    /// Any other payload, or one too short for its type.
+    Other {
+        payload_type: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// Mastering display colour volume, in the units of the codec's syntax.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MasteringDisplay {
+    /// x, y chromaticity of the green, blue and red primaries for H.264/HEVC, red, green and
+    /// blue for AV1.
+    pub primaries: [(u16, u16); 3],
+    pub white_point: (u16, u16),
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+/// Content light level in cd/m².
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ContentLightLevel {
+    pub max_content_light_level: u16,
+    pub max_frame_average_light_level: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TimeCode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u16,
+    pub counting_type: u8,
+    pub discontinuity: bool,
+    pub dropped_frames: bool,
+    pub time_offset: u32,
+}
+
+impl SeiMessage {
+    /// Parses a payload as reported by the parser for `codec`.
+    pub(crate) fn parse(codec: Codec, payload_type: u8, payload: &[u8]) -> Self {
+        let parsed = match (codec, payload_type) {
+            (Codec::AV1, AV1_METADATA_ITUT_T35) => {
+                Some(SeiMessage::UserDataRegistered(payload.to_vec()))
+            }
+            (Codec::AV1, AV1_METADATA_HDR_MDCV) => {
+                mastering_display(payload).map(SeiMessage::MasteringDisplay)
+            }
+            (Codec::AV1, AV1_METADATA_HDR_CLL) => {
+                content_light_level(payload).map(SeiMessage::ContentLightLevel)
+            }
+            (Codec::AV1, AV1_METADATA_TIMECODE) => {
+                av1_time_code(payload).map(|time_code| SeiMessage::TimeCode(vec![time_code]))
+            }
+            (Codec::AV1, _) => None,
+            (_, SEI_USER_DATA_REGISTERED) => Some(SeiMessage::UserDataRegistered(payload.to_vec())),
+            (_, SEI_USER_DATA_UNREGISTERED) if payload.len() >= 16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(&payload[..16]);
+                Some(SeiMessage::UserDataUnregistered {
+                    uuid,
+                    data: payload[16..].to_vec(),
+                })
+            }
+            (_, SEI_MASTERING_DISPLAY_COLOUR_VOLUME) => {
+                mastering_display(payload).map(SeiMessage::MasteringDisplay)
+            }
+            (_, SEI_CONTENT_LIGHT_LEVEL) => {
+                content_light_level(payload).map(SeiMessage::ContentLightLevel)
+            }
+            (Codec::H264, SEI_PIC_TIMING) | (Codec::HEVC, SEI_TIME_CODE) => {
+                driver_time_codes(payload).map(SeiMessage::TimeCode)
+            }
+            _ => None,
+        };
+
+        parsed.unwrap_or_else(|| SeiMessage::Other {
+            payload_type,
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    (data[offset] as u16) << 8 | data[offset + 1] as u16
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    (u16_at(data, offset) as u32) << 16 | u16_at(data, offset + 2) as u32
+}
+
+fn mastering_display(payload: &[u8]) -> Option<MasteringDisplay> {
+    if payload.len() < 24 {
+        return None;
+    }
+    let point = |offset| (u16_at(payload, offset), u16_at(payload, offset + 2));
+
+    Some(MasteringDisplay {
+        primaries: [point(0), point(4), point(8)],
+        white_point: point(12),
+        max_luminance: u32_at(payload, 16),
+        min_luminance: u32_at(payload, 20),
+    })
+}
+
+fn content_light_level(payload: &[u8]) -> Option<ContentLightLevel> {
+    if payload.len() < 4 {
+        return None;
+    }
+
+    Some(ContentLightLevel {
+        max_content_light_level: u16_at(payload, 0),
+        max_frame_average_light_level: u16_at(payload, 2),
+    })
+}
+
+/// Reads the time codes the driver decodes from H.264 picture timing and HEVC time code SEI.
+fn driver_time_codes(payload: &[u8]) -> Option<Vec<TimeCode>> {
+    // TIMECODE for H.264 has the same layout as HEVCSEITIMECODE
+    if payload.len() < std::mem::size_of::<ffi::cuvid::HEVCSEITIMECODE>() {
+        return None;
+    }
+    let time_codes =
+        unsafe { std::ptr::read_unaligned(payload.as_ptr() as *const ffi::cuvid::HEVCSEITIMECODE) };
+
+    Some(
+        time_codes
+            .time_code_set
+            .iter()
+            .take(time_codes.num_clock_ts as usize)
+            .filter(|set| set.clock_timestamp_flag != 0)
+            .map(|set| TimeCode {
+                hours: set.hours_value,
+                minutes: set.minutes_value,
+                seconds: set.seconds_value,
+                frames: set.n_frames,
+                counting_type: set.counting_type,
+                discontinuity: set.discontinuity_flag != 0,
+                dropped_frames: set.cnt_dropped_flag != 0,
+                time_offset: set.time_offset_value,
+            })
+            .collect(),
+    )
+}
+
+/// Parses the bit-packed AV1 timecode metadata.
+fn av1_time_code(payload: &[u8]) -> Option<TimeCode> {
+    let mut bits = BitReader {
+        data: payload,
+        pos: 0,
+    };
+    let mut time_code = TimeCode {
+        counting_type: bits.read(5)? as _,
+        ..Default::default()
+    };
+    let full_timestamp = bits.read(1)? != 0;
+    time_code.discontinuity = bits.read(1)? != 0;
+    time_code.dropped_frames = bits.read(1)? != 0;
+    time_code.frames = bits.read(9)? as _;
+    if full_timestamp {
+        time_code.seconds = bits.read(6)? as _;
+        time_code.minutes = bits.read(6)? as _;
+        time_code.hours = bits.read(5)? as _;
+    } else if bits.read(1)? != 0 {
+        time_code.seconds = bits.read(6)? as _;
+        if bits.read(1)? != 0 {
+            time_code.minutes = bits.read(6)? as _;
+            if bits.read(1)? != 0 {
+                time_code.hours = bits.read(5)? as _;
+            }
+        }
+    }
+    let time_offset_length = bits.read(5)?;
+    if time_offset_length > 0 {
+        time_code.time_offset = bits.read(time_offset_length)?;
+    }
+
+    Some(time_code)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = *self.data.get(self.pos / 8)?;
+            value = value << 1 | ((byte >> (7 - self.pos % 8)) & 1) as u32;
+            self.pos += 1;
+        }
+
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ContentLightLevel, SeiMessage, TimeCode};
+    use crate::cuvid::Codec;
+
+    #[test]
+    fn parse_sei() {
+        assert_eq!(
+            SeiMessage::parse(Codec::HEVC, 144, &[0x03, 0xe8, 0x01, 0x90]),
+            SeiMessage::ContentLightLevel(ContentLightLevel {
+                max_content_light_level: 1000,
+                max_frame_average_light_level: 400,
+            })
+        );
+        assert_eq!(
+            SeiMessage::parse(Codec::H264, 5, &[7; 18]),
+            SeiMessage::UserDataUnregistered {
+                uuid: [7; 16],
+                data: vec![7, 7],
+            }
+        );
+        // too short for a mastering display colour volume
+        assert_eq!(
+            SeiMessage::parse(Codec::HEVC, 137, &[1, 2]),
+            SeiMessage::Other {
+                payload_type: 137,
+                payload: vec![1, 2],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_av1_time_code() {
+        // counting type 0, full timestamp, 12 frames, 01:02:03, no time offset
+        let payload = [
+            0b0000_0100,
+            0b0000_0110,
+            0b0000_0110,
+            0b0001_0000,
+            0b0100_0000,
+        ];
+        assert_eq!(
+            SeiMessage::parse(Codec::AV1, 5, &payload),
+            SeiMessage::TimeCode(vec![TimeCode {
+                hours: 1,
+                minutes: 2,
+                seconds: 3,
+                frames: 12,
+                ..Default::default()
+            }])
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use super::ffi;
+
+/// Outcome of decoding a picture, as reported by `cuvidGetDecodeStatus`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum DecodeStatus {
+    Success = ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Success,
+    InProgress = ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_InProgress,
+    Error = ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Error,
+    /// The picture had errors that the decoder concealed.
+    ErrorConcealed = ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Error_Concealed,
+}
+
+impl DecodeStatus {
+    /// Maps the raw status, `None` if the driver did not report one.
+    pub(crate) fn from_raw(status: ffi::cuvid::cuvidDecodeStatus) -> Option<Self> {
+        match status {
+            ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Success => {
+                Some(DecodeStatus::Success)
+            }
+            ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_InProgress => {
+                Some(DecodeStatus::InProgress)
+            }
+            ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Error => Some(DecodeStatus::Error),
+            ffi::cuvid::cuvidDecodeStatus_enum_cuvidDecodeStatus_Error_Concealed => {
+                Some(DecodeStatus::ErrorConcealed)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_corrupted(self) -> bool {
+        self == DecodeStatus::Error || self == DecodeStatus::ErrorConcealed
+    }
+}
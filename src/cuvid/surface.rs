@@ -1,4 +1,7 @@
+use std::convert::TryFrom;
+
 use super::ffi;
+use super::DecoderError;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u32)]
@@ -15,22 +18,55 @@ impl Into<ffi::cuvid::cudaVideoSurfaceFormat> for VideoSurfaceFormat {
     }
 }
 
-impl From<ffi::cuvid::cudaVideoSurfaceFormat> for VideoSurfaceFormat {
-    fn from(format: ffi::cuvid::cudaVideoSurfaceFormat) -> Self {
+impl VideoSurfaceFormat {
+    pub fn bytes_per_sample(self) -> u32 {
+        match self {
+            VideoSurfaceFormat::NV12 | VideoSurfaceFormat::YUV444 => 1,
+            VideoSurfaceFormat::P016 | VideoSurfaceFormat::YUV444_16 => 2,
+        }
+    }
+
+    /// Rows taken by all the planes of a `height` pixel high picture, stacked.
+    pub fn rows(self, height: u32) -> u32 {
+        match self {
+            VideoSurfaceFormat::NV12 | VideoSurfaceFormat::P016 => height + height.div_ceil(2),
+            VideoSurfaceFormat::YUV444 | VideoSurfaceFormat::YUV444_16 => height * 3,
+        }
+    }
+
+    /// First row and row count of each plane of a `height` pixel high picture in a decoder
+    /// surface `surface_height` rows high, and the row the plane starts at once stacked.
+    pub(crate) fn planes(self, height: u32, surface_height: u32) -> Vec<(u32, u32, u32)> {
+        match self {
+            VideoSurfaceFormat::NV12 | VideoSurfaceFormat::P016 => vec![
+                (0, height, 0),
+                ((surface_height + 1) & !1, height.div_ceil(2), height),
+            ],
+            VideoSurfaceFormat::YUV444 | VideoSurfaceFormat::YUV444_16 => (0..3)
+                .map(|plane| (surface_height * plane, height, height * plane))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<ffi::cuvid::cudaVideoSurfaceFormat> for VideoSurfaceFormat {
+    type Error = DecoderError;
+
+    fn try_from(format: ffi::cuvid::cudaVideoSurfaceFormat) -> Result<Self, Self::Error> {
         match format {
             ffi::cuvid::cudaVideoSurfaceFormat_enum_cudaVideoSurfaceFormat_NV12 => {
-                VideoSurfaceFormat::NV12
+                Ok(VideoSurfaceFormat::NV12)
             }
             ffi::cuvid::cudaVideoSurfaceFormat_enum_cudaVideoSurfaceFormat_P016 => {
-                VideoSurfaceFormat::P016
+                Ok(VideoSurfaceFormat::P016)
             }
             ffi::cuvid::cudaVideoSurfaceFormat_enum_cudaVideoSurfaceFormat_YUV444 => {
-                VideoSurfaceFormat::YUV444
+                Ok(VideoSurfaceFormat::YUV444)
             }
             ffi::cuvid::cudaVideoSurfaceFormat_enum_cudaVideoSurfaceFormat_YUV444_16Bit => {
-                VideoSurfaceFormat::YUV444_16
+                Ok(VideoSurfaceFormat::YUV444_16)
             }
-            _ => panic!("Invalid cuda video surface formate"),
+            _ => Err(DecoderError::UnknownSurfaceFormat(format)),
         }
     }
 }
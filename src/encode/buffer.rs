@@ -0,0 +1,367 @@
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::slice;
+
+use ffi::encode_api::NVENCSTATUS;
+use ffi::encode_api::NV_ENC_INPUT_PTR;
+use ffi::encode_api::NV_ENC_OUTPUT_PTR;
+
+use super::{BufferFormat, Encoder};
+
+/// An input surface allocated by the encoder with `nvEncCreateInputBuffer`.
+pub struct InputBuffer {
+    pub(crate) inner: NV_ENC_INPUT_PTR,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) format: BufferFormat,
+}
+
+impl InputBuffer {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> BufferFormat {
+        self.format
+    }
+}
+
+/// A fixed set of input buffers handed out for filling and returned for reuse once dropped.
+pub struct InputBufferPool<'a> {
+    encoder: &'a Encoder<'a>,
+    free: RefCell<Vec<InputBuffer>>,
+}
+
+impl<'a> InputBufferPool<'a> {
+    pub(crate) fn new(
+        encoder: &'a Encoder<'a>,
+        width: u32,
+        height: u32,
+        format: BufferFormat,
+        count: usize,
+    ) -> Result<Self, NVENCSTATUS> {
+        let pool = Self {
+            encoder,
+            free: RefCell::new(Vec::with_capacity(count)),
+        };
+
+        // buffers created so far are destroyed with the pool if one of the allocations fails
+        for _ in 0..count {
+            let buffer = encoder.create_input_buffer(width, height, format)?;
+            pool.free.borrow_mut().push(buffer);
+        }
+
+        Ok(pool)
+    }
+
+    /// Takes a free buffer out of the pool, returns `None` if all of them are in use.
+    pub fn acquire(&self) -> Option<PooledInput<'_, 'a>> {
+        self.free.borrow_mut().pop().map(|buffer| PooledInput {
+            pool: self,
+            buffer: Some(buffer),
+        })
+    }
+
+    /// Number of buffers that can currently be acquired.
+    pub fn available(&self) -> usize {
+        self.free.borrow().len()
+    }
+}
+
+impl Drop for InputBufferPool<'_> {
+    fn drop(&mut self) {
+        for buffer in self.free.get_mut().drain(..) {
+            if let Err(err) = self.encoder.destroy_input_buffer(buffer) {
+                tracing::error!("Failed to destroy input buffer: {}", err);
+            }
+        }
+    }
+}
+
+/// A buffer borrowed from an `InputBufferPool`, returned to the pool on drop.
+pub struct PooledInput<'p, 'a: 'p> {
+    pool: &'p InputBufferPool<'a>,
+    buffer: Option<InputBuffer>,
+}
+
+impl Deref for PooledInput<'_, '_> {
+    type Target = InputBuffer;
+
+    fn deref(&self) -> &Self::Target {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledInput<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.free.borrow_mut().push(buffer);
+        }
+    }
+}
+
+/// Host mapping of an input buffer obtained with `Encoder::lock_input`.
+///
+/// The buffer is unlocked by `Encoder::unlock_input` or, failing that, when the lock is dropped.
+pub struct LockedInput<'a> {
+    pub(crate) encoder: &'a Encoder<'a>,
+    pub(crate) buffer: &'a InputBuffer,
+    pub(crate) ptr: *mut u8,
+    pub(crate) pitch: usize,
+    pub(crate) locked: bool,
+}
+
+impl LockedInput<'_> {
+    /// Row pitch in bytes of the first plane.
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    /// Returns the pitch of `plane` and a view over its rows, padding included.
+    pub fn plane_mut(&mut self, plane: usize) -> Option<(&mut [u8], usize)> {
+        let format = self.buffer.format;
+        if plane >= format.planes() {
+            return None;
+        }
+
+        let (offset, pitch) = format.plane_offset(plane, self.buffer.height, self.pitch);
+        let (_, rows) = format.plane_size(plane, self.buffer.width, self.buffer.height);
+        let data = unsafe { slice::from_raw_parts_mut(self.ptr.add(offset), pitch * rows) };
+
+        Some((data, pitch))
+    }
+
+    /// Copies host planes, given as `(data, stride)` pairs, into the buffer.
+    ///
+    /// The number of planes and their sizes must match the buffer format.
+    pub fn upload(&mut self, planes: &[(&[u8], usize)]) -> Result<(), NVENCSTATUS> {
+        let format = self.buffer.format;
+        let (width, height) = (self.buffer.width, self.buffer.height);
+        if planes.len() != format.planes() {
+            return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INVALID_PARAM);
+        }
+
+        for (index, &(src, src_stride)) in planes.iter().enumerate() {
+            let (row_bytes, rows) = format.plane_size(index, width, height);
+            let (dst, dst_pitch) = self.plane_mut(index).unwrap();
+            if !copy_plane(dst, dst_pitch, src, src_stride, row_bytes, rows) {
+                return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INVALID_PARAM);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn upload_nv12(
+        &mut self,
+        y: &[u8],
+        y_stride: usize,
+        uv: &[u8],
+        uv_stride: usize,
+    ) -> Result<(), NVENCSTATUS> {
+        self.expect_format(BufferFormat::NV12)?;
+        self.upload(&[(y, y_stride), (uv, uv_stride)])
+    }
+
+    pub fn upload_i420(
+        &mut self,
+        y: &[u8],
+        y_stride: usize,
+        u: &[u8],
+        u_stride: usize,
+        v: &[u8],
+        v_stride: usize,
+    ) -> Result<(), NVENCSTATUS> {
+        self.expect_format(BufferFormat::IYUV)?;
+        self.upload(&[(y, y_stride), (u, u_stride), (v, v_stride)])
+    }
+
+    /// Strides are in bytes, samples are little endian 16 bit words.
+    pub fn upload_p010(
+        &mut self,
+        y: &[u8],
+        y_stride: usize,
+        uv: &[u8],
+        uv_stride: usize,
+    ) -> Result<(), NVENCSTATUS> {
+        self.expect_format(BufferFormat::YUV420_10)?;
+        self.upload(&[(y, y_stride), (uv, uv_stride)])
+    }
+
+    pub fn upload_argb(&mut self, data: &[u8], stride: usize) -> Result<(), NVENCSTATUS> {
+        self.expect_format(BufferFormat::ARGB)?;
+        self.upload(&[(data, stride)])
+    }
+
+    pub fn upload_abgr(&mut self, data: &[u8], stride: usize) -> Result<(), NVENCSTATUS> {
+        self.expect_format(BufferFormat::ABGR)?;
+        self.upload(&[(data, stride)])
+    }
+
+    /// Fills an NV12 buffer meant as the `alpha` input of `Encoder::encode_picture`.
+    ///
+    /// The alpha samples go in the luma plane and the chroma plane is set to 0x80, as NVENC
+    /// recommends, so nothing left in a pooled buffer ends up in the alpha layer.
+    pub fn upload_alpha(&mut self, alpha: &[u8], stride: usize) -> Result<(), NVENCSTATUS> {
+        self.expect_format(BufferFormat::NV12)?;
+        let (width, height) = (self.buffer.width, self.buffer.height);
+        let (offset, chroma_pitch) = BufferFormat::NV12.plane_offset(1, height, self.pitch);
+        let (_, chroma_rows) = BufferFormat::NV12.plane_size(1, width, height);
+        let data =
+            unsafe { slice::from_raw_parts_mut(self.ptr, offset + chroma_pitch * chroma_rows) };
+        if !write_alpha(data, self.pitch, width, height, alpha, stride) {
+            return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INVALID_PARAM);
+        }
+
+        Ok(())
+    }
+
+    fn expect_format(&self, format: BufferFormat) -> Result<(), NVENCSTATUS> {
+        if self.buffer.format != format {
+            return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INVALID_PARAM);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for LockedInput<'_> {
+    fn drop(&mut self) {
+        if self.locked && self.encoder.unlock_raw(self.buffer).is_err() {
+            tracing::error!("Failed to unlock input buffer.");
+        }
+    }
+}
+
+/// An output buffer receiving the bitstream of one encoded picture.
+pub struct BitstreamBuffer<'a> {
+    pub(crate) encoder: &'a Encoder<'a>,
+    pub(crate) inner: NV_ENC_OUTPUT_PTR,
+}
+
+impl Drop for BitstreamBuffer<'_> {
+    fn drop(&mut self) {
+        if self.encoder.destroy_bitstream_buffer(self.inner).is_err() {
+            tracing::error!("Failed to destroy bitstream buffer.");
+        }
+    }
+}
+
+/// Bitstream of an encoded picture copied out of a `BitstreamBuffer`.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub timestamp: u64,
+    pub keyframe: bool,
+    /// Bytes of `data` belonging to the alpha layer, zero without alpha layer encoding.
+    pub alpha_layer_size: usize,
+    /// Temporal layer of the picture, always 0 without temporal SVC.
+    pub temporal_id: u32,
+}
+
+/// Copies `rows` rows of `row_bytes` bytes between pitched buffers, returns `false` if either is too small.
+fn copy_plane(
+    dst: &mut [u8],
+    dst_pitch: usize,
+    src: &[u8],
+    src_stride: usize,
+    row_bytes: usize,
+    rows: usize,
+) -> bool {
+    if rows == 0 {
+        return true;
+    }
+    if src_stride < row_bytes || dst_pitch < row_bytes {
+        return false;
+    }
+    if src.len() < (rows - 1) * src_stride + row_bytes
+        || dst.len() < (rows - 1) * dst_pitch + row_bytes
+    {
+        return false;
+    }
+
+    for row in 0..rows {
+        let src_row = &src[row * src_stride..row * src_stride + row_bytes];
+        dst[row * dst_pitch..row * dst_pitch + row_bytes].copy_from_slice(src_row);
+    }
+
+    true
+}
+
+/// Copies `alpha` to the luma plane of the NV12 buffer `data` and sets its chroma plane to 0x80,
+/// returns `false` if either buffer is too small.
+fn write_alpha(
+    data: &mut [u8],
+    pitch: usize,
+    width: u32,
+    height: u32,
+    alpha: &[u8],
+    stride: usize,
+) -> bool {
+    let format = BufferFormat::NV12;
+    let (row_bytes, rows) = format.plane_size(0, width, height);
+    if !copy_plane(data, pitch, alpha, stride, row_bytes, rows) {
+        return false;
+    }
+
+    let (offset, chroma_pitch) = format.plane_offset(1, height, pitch);
+    let (row_bytes, rows) = format.plane_size(1, width, height);
+    if rows > 0 && data.len() < offset + (rows - 1) * chroma_pitch + row_bytes {
+        return false;
+    }
+    for row in 0..rows {
+        let start = offset + row * chroma_pitch;
+        data[start..start + row_bytes].fill(0x80);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nv12_layout() {
+        let format = BufferFormat::NV12;
+        assert_eq!(format.plane_size(0, 5, 3), (5, 3));
+        assert_eq!(format.plane_size(1, 5, 3), (6, 2));
+        assert_eq!(format.plane_offset(1, 3, 8), (24, 8));
+    }
+
+    #[test]
+    fn iyuv_layout() {
+        let format = BufferFormat::IYUV;
+        assert_eq!(format.plane_offset(1, 4, 16), (64, 8));
+        assert_eq!(format.plane_offset(2, 4, 16), (80, 8));
+        assert_eq!(format.plane_size(2, 6, 4), (3, 2));
+    }
+
+    #[test]
+    fn copy_pitched_plane() {
+        let src = [1, 2, 3, 0, 4, 5, 6];
+        let mut dst = [0u8; 8];
+        assert!(copy_plane(&mut dst, 4, &src, 4, 3, 2));
+        assert_eq!(dst, [1, 2, 3, 0, 4, 5, 6, 0]);
+
+        assert!(!copy_plane(&mut dst, 4, &src[..6], 4, 3, 2));
+        assert!(!copy_plane(&mut dst, 2, &src, 4, 3, 2));
+    }
+
+    #[test]
+    fn alpha_layout() {
+        // stale samples everywhere, the padding keeps them
+        let mut data = [7u8; 4 * 3 + 4 * 2];
+        assert!(write_alpha(&mut data, 4, 2, 3, &[1, 2, 3, 4, 5, 6], 2));
+        assert_eq!(
+            data,
+            [1, 2, 7, 7, 3, 4, 7, 7, 5, 6, 7, 7, 0x80, 0x80, 7, 7, 0x80, 0x80, 7, 7]
+        );
+
+        assert!(!write_alpha(&mut data[..17], 4, 2, 3, &[0; 6], 2));
+    }
+}
@@ -0,0 +1,131 @@
+use ffi::encode_api::GUID as NvGUID;
+use ffi::encode_api::NVENCSTATUS;
+use ffi::encode_api::NV_ENC_CONFIG;
+use ffi::encode_api::NV_ENC_TUNING_INFO;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    H264,
+    HEVC,
+    AV1,
+}
+
+impl Codec {
+    pub fn guid(self) -> NvGUID {
+        match self {
+            Codec::H264 => ffi::constants::encode_api::NV_ENC_CODEC_H264_GUID,
+            Codec::HEVC => ffi::constants::encode_api::NV_ENC_CODEC_HEVC_GUID,
+            Codec::AV1 => ffi::constants::encode_api::NV_ENC_CODEC_AV1_GUID,
+        }
+    }
+}
+
+/// Encoder settings, obtained from a driver preset with `Encoder::preset_config`.
+#[derive(Clone, Copy)]
+pub struct EncodeConfig {
+    pub(crate) codec: Codec,
+    pub(crate) preset: NvGUID,
+    pub(crate) tuning: NV_ENC_TUNING_INFO,
+    pub(crate) inner: NV_ENC_CONFIG,
+}
+
+impl EncodeConfig {
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Gives access to the settings that do not have a dedicated setter.
+    pub fn as_raw_mut(&mut self) -> &mut NV_ENC_CONFIG {
+        &mut self.inner
+    }
+
+    /// Encodes an auxiliary HEVC layer carrying the alpha channel of the input.
+    ///
+    /// The target bitrate is split `bitrate_ratio : 1` between the base and the alpha layer.
+    pub fn enable_alpha_layer(&mut self, bitrate_ratio: u32) -> Result<(), NVENCSTATUS> {
+        if self.codec != Codec::HEVC || bitrate_ratio == 0 {
+            return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INVALID_PARAM);
+        }
+
+        unsafe {
+            self.inner
+                .encodeCodecConfig
+                .hevcConfig
+                .set_enableAlphaLayerEncoding(1);
+        }
+        self.inner.rcParams.alphaLayerBitrateRatio = bitrate_ratio;
+
+        Ok(())
+    }
+
+    pub fn alpha_layer(&self) -> bool {
+        self.codec == Codec::HEVC
+            && unsafe {
+                self.inner
+                    .encodeCodecConfig
+                    .hevcConfig
+                    .enableAlphaLayerEncoding()
+            } != 0
+    }
+
+    /// Codes the stream with `layers` temporal layers, numbered from 0 (base) upwards.
+    ///
+    /// H.264 enables temporal SVC. For HEVC and AV1 `Encoder::encode_picture` assigns the layer
+    /// of each picture in a dyadic hierarchy. `Packet::temporal_id` reports the layer of each
+    /// picture.
+    pub fn enable_temporal_svc(&mut self, layers: u32) -> Result<(), NVENCSTATUS> {
+        if layers == 0 {
+            return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INVALID_PARAM);
+        }
+
+        let codec_config = &mut self.inner.encodeCodecConfig;
+        unsafe {
+            match self.codec {
+                Codec::H264 => {
+                    let h264 = &mut codec_config.h264Config;
+                    h264.set_enableTemporalSVC(1);
+                    h264.numTemporalLayers = layers;
+                    h264.maxTemporalLayers = layers;
+                    // The DPB has to hold the references of every layer above the base.
+                    let refs = layers.saturating_sub(2) * 2;
+                    if h264.maxNumRefFrames != 0 && h264.maxNumRefFrames < refs {
+                        h264.maxNumRefFrames = refs;
+                    }
+                }
+                Codec::HEVC => {
+                    let hevc = &mut codec_config.hevcConfig;
+                    hevc.set_enableTemporalSVC(1);
+                    hevc.numTemporalLayers = layers;
+                    hevc.maxTemporalLayersMinus1 = layers - 1;
+                }
+                Codec::AV1 => {
+                    let av1 = &mut codec_config.av1Config;
+                    av1.set_enableTemporalSVC(1);
+                    av1.numTemporalLayers = layers;
+                    av1.maxTemporalLayersMinus1 = layers - 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of temporal layers, 1 unless `enable_temporal_svc` was used.
+    pub fn temporal_layers(&self) -> u32 {
+        let codec_config = &self.inner.encodeCodecConfig;
+        unsafe {
+            match self.codec {
+                Codec::H264 if codec_config.h264Config.enableTemporalSVC() != 0 => {
+                    codec_config.h264Config.numTemporalLayers.max(1)
+                }
+                Codec::HEVC if codec_config.hevcConfig.enableTemporalSVC() != 0 => {
+                    codec_config.hevcConfig.numTemporalLayers.max(1)
+                }
+                Codec::AV1 if codec_config.av1Config.enableTemporalSVC() != 0 => {
+                    codec_config.av1Config.numTemporalLayers.max(1)
+                }
+                _ => 1,
+            }
+        }
+    }
+}
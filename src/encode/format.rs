@@ -0,0 +1,62 @@
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum BufferFormat {
+    /// Semi-planar YUV 4:2:0, a luma plane followed by an interleaved UV plane.
+    NV12 = ffi::encode_api::_NV_ENC_BUFFER_FORMAT_NV_ENC_BUFFER_FORMAT_NV12,
+    /// Planar YUV 4:2:0 (I420), a luma plane followed by the U and V planes.
+    IYUV = ffi::encode_api::_NV_ENC_BUFFER_FORMAT_NV_ENC_BUFFER_FORMAT_IYUV,
+    /// 10 bit semi-planar YUV 4:2:0 (P010), 16 bit samples with the data in the upper bits.
+    YUV420_10 = ffi::encode_api::_NV_ENC_BUFFER_FORMAT_NV_ENC_BUFFER_FORMAT_YUV420_10BIT,
+    /// Packed 8 bit ARGB, stored as B, G, R, A bytes in memory.
+    ARGB = ffi::encode_api::_NV_ENC_BUFFER_FORMAT_NV_ENC_BUFFER_FORMAT_ARGB,
+    /// Packed 8 bit ABGR, stored as R, G, B, A bytes in memory.
+    ABGR = ffi::encode_api::_NV_ENC_BUFFER_FORMAT_NV_ENC_BUFFER_FORMAT_ABGR,
+}
+
+impl BufferFormat {
+    /// Number of planes the format is split into.
+    pub fn planes(self) -> usize {
+        match self {
+            BufferFormat::NV12 | BufferFormat::YUV420_10 => 2,
+            BufferFormat::IYUV => 3,
+            BufferFormat::ARGB | BufferFormat::ABGR => 1,
+        }
+    }
+
+    /// Returns `(row_bytes, rows)` of the visible part of `plane` for a `width`x`height` picture.
+    pub fn plane_size(self, plane: usize, width: u32, height: u32) -> (usize, usize) {
+        let (width, height) = (width as usize, height as usize);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+
+        match (self, plane) {
+            (BufferFormat::NV12, 0) | (BufferFormat::IYUV, 0) => (width, height),
+            (BufferFormat::NV12, _) => (chroma_width * 2, chroma_height),
+            (BufferFormat::IYUV, _) => (chroma_width, chroma_height),
+            (BufferFormat::YUV420_10, 0) => (width * 2, height),
+            (BufferFormat::YUV420_10, _) => (chroma_width * 4, chroma_height),
+            (BufferFormat::ARGB, _) | (BufferFormat::ABGR, _) => (width * 4, height),
+        }
+    }
+
+    /// Returns `(offset, pitch)` of `plane` inside a locked buffer of `height` rows and `pitch` bytes.
+    pub fn plane_offset(self, plane: usize, height: u32, pitch: usize) -> (usize, usize) {
+        let height = height as usize;
+        let luma_size = pitch * height;
+
+        match (self, plane) {
+            (_, 0) => (0, pitch),
+            (BufferFormat::IYUV, 1) => (luma_size, pitch.div_ceil(2)),
+            (BufferFormat::IYUV, _) => {
+                let chroma_pitch = pitch.div_ceil(2);
+                (luma_size + chroma_pitch * height.div_ceil(2), chroma_pitch)
+            }
+            (_, _) => (luma_size, pitch),
+        }
+    }
+}
+
+impl From<BufferFormat> for ffi::encode_api::NV_ENC_BUFFER_FORMAT {
+    fn from(format: BufferFormat) -> Self {
+        format as ffi::encode_api::NV_ENC_BUFFER_FORMAT
+    }
+}
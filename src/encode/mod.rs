@@ -1,17 +1,53 @@
+use std::cell::Cell;
+use std::fmt;
 use std::ops::Deref;
 use std::{ffi::c_void, mem};
 
 use ffi::encode_api::GUID as NvGUID;
+use ffi::encode_api::NVENCAPI_MAJOR_VERSION;
+use ffi::encode_api::NVENCAPI_MINOR_VERSION;
 use ffi::encode_api::NVENCAPI_VERSION;
 // use ffi::encode_api::NV_ENCODE_API_FUNCTION_LIST;
 use ffi::encode_api::NVENCSTATUS;
 use ffi::encode_api::NV_ENCODE_API_FUNCTION_LIST;
+use ffi::encode_api::NV_ENC_CAPS;
+use ffi::encode_api::NV_ENC_CAPS_PARAM;
+use ffi::encode_api::NV_ENC_CREATE_BITSTREAM_BUFFER;
+use ffi::encode_api::NV_ENC_CREATE_INPUT_BUFFER;
+use ffi::encode_api::NV_ENC_INITIALIZE_PARAMS;
+use ffi::encode_api::NV_ENC_LOCK_BITSTREAM;
+use ffi::encode_api::NV_ENC_LOCK_INPUT_BUFFER;
 use ffi::encode_api::NV_ENC_OPEN_ENCODE_SESSION_EX_PARAMS;
+use ffi::encode_api::NV_ENC_OUTPUT_PTR;
+use ffi::encode_api::NV_ENC_PIC_PARAMS;
+use ffi::encode_api::NV_ENC_PRESET_CONFIG;
+use ffi::encode_api::NV_ENC_TUNING_INFO;
 use ffi::encode_api::_NV_ENC_DEVICE_TYPE_NV_ENC_DEVICE_TYPE_CUDA;
+use ffi::constants::encode_api::NV_ENC_CAPS_PARAM_VER;
+use ffi::constants::encode_api::NV_ENC_CONFIG_VER;
+use ffi::constants::encode_api::NV_ENC_CREATE_BITSTREAM_BUFFER_VER;
+use ffi::constants::encode_api::NV_ENC_CREATE_INPUT_BUFFER_VER;
+use ffi::constants::encode_api::NV_ENC_INITIALIZE_PARAMS_VER;
+use ffi::constants::encode_api::NV_ENC_LOCK_BITSTREAM_VER;
+use ffi::constants::encode_api::NV_ENC_LOCK_INPUT_BUFFER_VER;
 use ffi::constants::encode_api::NV_ENC_OPEN_ENCODE_SESSION_EX_PARAMS_VER;
+use ffi::constants::encode_api::NV_ENC_PIC_PARAMS_VER;
+use ffi::constants::encode_api::NV_ENC_PRESET_CONFIG_VER;
 
 use crate::cuda::context::CuContext;
 
+mod buffer;
+mod config;
+mod format;
+mod pool;
+
+pub use self::buffer::{
+    BitstreamBuffer, InputBuffer, InputBufferPool, LockedInput, Packet, PooledInput,
+};
+pub use self::config::{Codec, EncodeConfig};
+pub use self::format::BufferFormat;
+pub use self::pool::{PooledEncoder, SessionError, SessionPool};
+
 pub trait EncodeResult {
     fn ok(&self) -> bool;
     fn err(&self) -> Result<(), Self>
@@ -47,15 +83,111 @@ impl EncodeResult for NVENCSTATUS {
     }
 }
 
+/// Errors raised when the installed driver cannot serve the NVENC API this crate was built against.
+///
+/// `Encode::new` returns these boxed, so callers wanting to fall back to a software
+/// encoder can `downcast_ref::<EncodeError>()` the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The driver does not export the named entry point.
+    MissingEntryPoint(&'static str),
+    /// The driver supports an older API than the one the bindings were generated from.
+    UnsupportedVersion {
+        required: (u32, u32),
+        supported: (u32, u32),
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::MissingEntryPoint(name) => {
+                write!(f, "{} is not provided by the installed driver", name)
+            }
+            EncodeError::UnsupportedVersion {
+                required,
+                supported,
+            } => write!(
+                f,
+                "NVENC API {}.{} is required but the driver only supports {}.{}",
+                required.0, required.1, supported.0, supported.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Fails with `EncodeError::MissingEntryPoint` for the first entry point the driver left empty.
+macro_rules! require_entry_points {
+    ($api:expr, $($name:ident),+) => {
+        $(
+            if $api.$name.is_none() {
+                return Err(Box::new(EncodeError::MissingEntryPoint(stringify!($name))));
+            }
+        )+
+    };
+}
+
+/// Fetches an entry point validated by `Encode::new`, without panicking if it is somehow missing.
+macro_rules! entry_point {
+    ($encode:expr, $name:ident) => {
+        $encode
+            .api
+            .$name
+            .ok_or(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_UNIMPLEMENTED)?
+    };
+}
+
 pub struct Encode {
     pub(crate) lib: ffi::encode_api::nvidia_encode,
     pub(crate) api: NV_ENCODE_API_FUNCTION_LIST,
 }
 
+// SAFETY: `lib` owns the loaded library, which stays mapped for as long as `Encode` lives no
+// matter which thread drops it, and holds plain function pointers resolved from it. `api` is
+// filled in once by `NvEncodeAPICreateInstance` in `Encode::new` and only read afterwards: its
+// entries are function pointers into the driver, and its raw pointer fields are reserved ones
+// that stay null and are never dereferenced. The NVENC entry points themselves may be called
+// from any thread, the per-session state lives in `Encoder`, which is neither `Send` nor `Sync`.
+unsafe impl Send for Encode {}
+unsafe impl Sync for Encode {}
+
 impl Encode {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
         let library_name = libloading::library_filename("nvidia-encode");
         let lib = unsafe { ffi::encode_api::nvidia_encode::new(library_name) }?;
+        if lib.NvEncodeAPIGetMaxSupportedVersion.is_err() {
+            return Err(Box::new(EncodeError::MissingEntryPoint(
+                "NvEncodeAPIGetMaxSupportedVersion",
+            )));
+        }
+        if lib.NvEncodeAPICreateInstance.is_err() {
+            return Err(Box::new(EncodeError::MissingEntryPoint(
+                "NvEncodeAPICreateInstance",
+            )));
+        }
+
+        // Older drivers reject newer structure versions, so check before creating the instance
+        let required = (NVENCAPI_MAJOR_VERSION, NVENCAPI_MINOR_VERSION);
+        let supported = max_supported_version(&lib).map_err(|res| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("NvEncodeAPIGetMaxSupportedVersion = {}", res),
+            )
+        })?;
+        tracing::trace!(
+            "NVENC API version required = {:?}\t supported = {:?}",
+            required,
+            supported
+        );
+        if supported < required {
+            return Err(Box::new(EncodeError::UnsupportedVersion {
+                required,
+                supported,
+            }));
+        }
+
         let mut function_list: NV_ENCODE_API_FUNCTION_LIST = unsafe { mem::zeroed() };
         function_list.version = ffi::constants::encode_api::NV_ENCODE_API_FUNCTION_LIST_VER;
         {
@@ -67,17 +199,50 @@ impl Encode {
                 )));
             }
         }
+        require_entry_points!(
+            function_list,
+            nvEncOpenEncodeSessionEx,
+            nvEncGetEncodeGUIDCount,
+            nvEncGetEncodeGUIDs,
+            nvEncGetEncodeCaps,
+            nvEncGetEncodePresetConfigEx,
+            nvEncInitializeEncoder,
+            nvEncCreateInputBuffer,
+            nvEncDestroyInputBuffer,
+            nvEncLockInputBuffer,
+            nvEncUnlockInputBuffer,
+            nvEncCreateBitstreamBuffer,
+            nvEncDestroyBitstreamBuffer,
+            nvEncEncodePicture,
+            nvEncLockBitstream,
+            nvEncUnlockBitstream,
+            nvEncDestroyEncoder
+        );
+
         Ok(Self {
             lib,
             api: function_list,
         })
     }
 
-    pub fn new_encoder(&self, ctx: CuContext) -> Result<Encoder<'_>, NVENCSTATUS> {
+    /// Returns the newest NVENC API version supported by the installed driver as `(major, minor)`.
+    pub fn max_supported_version(&self) -> Result<(u32, u32), NVENCSTATUS> {
+        max_supported_version(&self.lib)
+    }
+
+    pub fn new_encoder<'a>(&'a self, ctx: CuContext<'a>) -> Result<Encoder<'a>, NVENCSTATUS> {
         Encoder::new(&self, ctx)
     }
 }
 
+fn max_supported_version(lib: &ffi::encode_api::nvidia_encode) -> Result<(u32, u32), NVENCSTATUS> {
+    let mut version = 0;
+    let res = unsafe { lib.NvEncodeAPIGetMaxSupportedVersion(&mut version) };
+
+    // The driver reports the version as (major << 4) | minor
+    res.result((version >> 4, version & 0xf))
+}
+
 impl Deref for Encode {
     type Target = ffi::encode_api::nvidia_encode;
 
@@ -88,12 +253,17 @@ impl Deref for Encode {
 
 pub struct Encoder<'a> {
     lib: &'a Encode,
+    // the session is bound to the context, so it has to outlive it
+    _ctx: CuContext<'a>,
     params: NV_ENC_OPEN_ENCODE_SESSION_EX_PARAMS,
     inner: *mut c_void,
+    // codec and layer count when `encode_picture` assigns the temporal layers
+    temporal_layers: Option<(Codec, u32)>,
+    pictures: Cell<u64>,
 }
 
 impl<'a> Encoder<'a> {
-    pub(crate) fn new(lib: &'a Encode, ctx: CuContext) -> Result<Self, NVENCSTATUS> {
+    pub(crate) fn new(lib: &'a Encode, ctx: CuContext<'a>) -> Result<Self, NVENCSTATUS> {
         let mut params: NV_ENC_OPEN_ENCODE_SESSION_EX_PARAMS =
             NV_ENC_OPEN_ENCODE_SESSION_EX_PARAMS {
                 version: NV_ENC_OPEN_ENCODE_SESSION_EX_PARAMS_VER,
@@ -106,42 +276,345 @@ impl<'a> Encoder<'a> {
             };
 
         let mut encoder = std::ptr::null_mut();
-        let open_session = lib
-            .api
-            .nvEncOpenEncodeSessionEx
-            .expect("nvEncOpenEncodeSessionEx not supported");
+        let open_session = entry_point!(lib, nvEncOpenEncodeSessionEx);
         let res = unsafe { open_session(&mut params, &mut encoder) };
         tracing::trace!("Create encoder = {}", res);
 
         // session will be dropped if there is an error causing NvEncDestroyEncoder to be called
         let session = Self {
             lib,
+            _ctx: ctx,
             params,
             inner: encoder,
+            temporal_layers: None,
+            pictures: Cell::new(0),
         };
 
         res.result(session)
     }
 
+    /// Queries a capability of `codec` on the device backing the session.
+    pub fn caps(&self, codec: Codec, cap: NV_ENC_CAPS) -> Result<i32, NVENCSTATUS> {
+        let mut params: NV_ENC_CAPS_PARAM = unsafe { mem::zeroed() };
+        params.version = NV_ENC_CAPS_PARAM_VER;
+        params.capsToQuery = cap;
+
+        let mut value = 0;
+        let get_encode_caps = entry_point!(self.lib, nvEncGetEncodeCaps);
+        let res = unsafe { get_encode_caps(self.inner, codec.guid(), &mut params, &mut value) };
+        tracing::trace!("Get encode caps {} = {}\t value = {}", cap, res, value);
+
+        res.result(value)
+    }
+
+    /// Returns the settings the driver uses for `preset` tuned for `tuning`.
+    pub fn preset_config(
+        &self,
+        codec: Codec,
+        preset: NvGUID,
+        tuning: NV_ENC_TUNING_INFO,
+    ) -> Result<EncodeConfig, NVENCSTATUS> {
+        let mut params: NV_ENC_PRESET_CONFIG = unsafe { mem::zeroed() };
+        params.version = NV_ENC_PRESET_CONFIG_VER;
+        params.presetCfg.version = NV_ENC_CONFIG_VER;
+
+        let get_preset_config = entry_point!(self.lib, nvEncGetEncodePresetConfigEx);
+        let res =
+            unsafe { get_preset_config(self.inner, codec.guid(), preset, tuning, &mut params) };
+        tracing::trace!("Get preset config = {}", res);
+
+        res.result(EncodeConfig {
+            codec,
+            preset,
+            tuning,
+            inner: params.presetCfg,
+        })
+    }
+
+    /// Initializes the session from `config` for `width`x`height` pictures at `frame_rate` (num, den).
+    pub fn initialize_with_config(
+        &mut self,
+        config: &mut EncodeConfig,
+        width: u32,
+        height: u32,
+        frame_rate: (u32, u32),
+    ) -> Result<(), NVENCSTATUS> {
+        if config.alpha_layer()
+            && self.caps(
+                config.codec,
+                ffi::encode_api::_NV_ENC_CAPS_NV_ENC_CAPS_SUPPORT_ALPHA_LAYER_ENCODING,
+            )? == 0
+        {
+            tracing::error!("Alpha layer encoding is not supported on this GPU");
+            return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_UNSUPPORTED_PARAM);
+        }
+
+        let layers = config.temporal_layers();
+        if layers > 1 {
+            if self.caps(
+                config.codec,
+                ffi::encode_api::_NV_ENC_CAPS_NV_ENC_CAPS_SUPPORT_TEMPORAL_SVC,
+            )? == 0
+            {
+                tracing::error!("Temporal SVC is not supported on this GPU");
+                return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_UNSUPPORTED_PARAM);
+            }
+
+            let max_layers = self.caps(
+                config.codec,
+                ffi::encode_api::_NV_ENC_CAPS_NV_ENC_CAPS_NUM_MAX_TEMPORAL_LAYERS,
+            )?;
+            if layers > max_layers as u32 {
+                tracing::error!(
+                    "{} temporal layers requested, the GPU supports {}",
+                    layers,
+                    max_layers
+                );
+                return Err(ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_UNSUPPORTED_PARAM);
+            }
+        }
+
+        let mut params: NV_ENC_INITIALIZE_PARAMS = unsafe { mem::zeroed() };
+        params.encodeGUID = config.codec.guid();
+        params.presetGUID = config.preset;
+        params.tuningInfo = config.tuning;
+        params.encodeWidth = width;
+        params.encodeHeight = height;
+        params.darWidth = width;
+        params.darHeight = height;
+        params.frameRateNum = frame_rate.0;
+        params.frameRateDen = frame_rate.1;
+        params.enablePTD = 1;
+        config.inner.version = NV_ENC_CONFIG_VER;
+        params.encodeConfig = &mut config.inner;
+
+        self.initialize(&mut params)?;
+        // H.264 temporal SVC picks the layers by itself
+        self.temporal_layers = Some((config.codec, layers))
+            .filter(|&(codec, layers)| codec != Codec::H264 && layers > 1);
+        self.pictures.set(0);
+
+        Ok(())
+    }
+
+    /// Initializes the session, it has to be called once before any buffer is allocated.
+    ///
+    /// `params.version` is filled in, everything else is up to the caller.
+    pub fn initialize(&mut self, params: &mut NV_ENC_INITIALIZE_PARAMS) -> Result<(), NVENCSTATUS> {
+        params.version = NV_ENC_INITIALIZE_PARAMS_VER;
+        let initialize = entry_point!(self.lib, nvEncInitializeEncoder);
+        let res = unsafe { initialize(self.inner, params) };
+        tracing::trace!("Initialize encoder = {}", res);
+
+        res.err()
+    }
+
+    /// Allocates `count` input buffers of the given size and format for reuse across frames.
+    pub fn input_buffer_pool(
+        &self,
+        width: u32,
+        height: u32,
+        format: BufferFormat,
+        count: usize,
+    ) -> Result<InputBufferPool<'_>, NVENCSTATUS> {
+        InputBufferPool::new(self, width, height, format, count)
+    }
+
+    pub(crate) fn create_input_buffer(
+        &self,
+        width: u32,
+        height: u32,
+        format: BufferFormat,
+    ) -> Result<InputBuffer, NVENCSTATUS> {
+        let mut params: NV_ENC_CREATE_INPUT_BUFFER = unsafe { mem::zeroed() };
+        params.version = NV_ENC_CREATE_INPUT_BUFFER_VER;
+        params.width = width;
+        params.height = height;
+        params.bufferFmt = format.into();
+
+        let create_input_buffer = entry_point!(self.lib, nvEncCreateInputBuffer);
+        let res = unsafe { create_input_buffer(self.inner, &mut params) };
+        tracing::trace!("Create input buffer = {}", res);
+
+        res.result(InputBuffer {
+            inner: params.inputBuffer,
+            width,
+            height,
+            format,
+        })
+    }
+
+    pub(crate) fn destroy_input_buffer(&self, buffer: InputBuffer) -> Result<(), NVENCSTATUS> {
+        let destroy_input_buffer = entry_point!(self.lib, nvEncDestroyInputBuffer);
+
+        unsafe { destroy_input_buffer(self.inner, buffer.inner) }.err()
+    }
+
+    /// Maps `buffer` into host memory so it can be filled by the CPU.
+    pub fn lock_input<'b>(
+        &'b self,
+        buffer: &'b InputBuffer,
+    ) -> Result<LockedInput<'b>, NVENCSTATUS> {
+        let mut params: NV_ENC_LOCK_INPUT_BUFFER = unsafe { mem::zeroed() };
+        params.version = NV_ENC_LOCK_INPUT_BUFFER_VER;
+        params.inputBuffer = buffer.inner;
+
+        let lock_input_buffer = entry_point!(self.lib, nvEncLockInputBuffer);
+        let res = unsafe { lock_input_buffer(self.inner, &mut params) };
+        tracing::trace!("Lock input buffer = {}\t pitch = {}", res, params.pitch);
+
+        res.result(LockedInput {
+            encoder: self,
+            buffer,
+            ptr: params.bufferDataPtr as *mut u8,
+            pitch: params.pitch as usize,
+            locked: true,
+        })
+    }
+
+    /// Hands a buffer filled through `lock_input` back to the encoder.
+    pub fn unlock_input(&self, mut locked: LockedInput) -> Result<(), NVENCSTATUS> {
+        locked.locked = false;
+
+        self.unlock_raw(locked.buffer)
+    }
+
+    pub(crate) fn unlock_raw(&self, buffer: &InputBuffer) -> Result<(), NVENCSTATUS> {
+        let unlock_input_buffer = entry_point!(self.lib, nvEncUnlockInputBuffer);
+
+        unsafe { unlock_input_buffer(self.inner, buffer.inner) }.err()
+    }
+
+    pub fn bitstream_buffer(&self) -> Result<BitstreamBuffer<'_>, NVENCSTATUS> {
+        let mut params: NV_ENC_CREATE_BITSTREAM_BUFFER = unsafe { mem::zeroed() };
+        params.version = NV_ENC_CREATE_BITSTREAM_BUFFER_VER;
+
+        let create_bitstream_buffer = entry_point!(self.lib, nvEncCreateBitstreamBuffer);
+        let res = unsafe { create_bitstream_buffer(self.inner, &mut params) };
+        tracing::trace!("Create bitstream buffer = {}", res);
+
+        res.result(BitstreamBuffer {
+            encoder: self,
+            inner: params.bitstreamBuffer,
+        })
+    }
+
+    pub(crate) fn destroy_bitstream_buffer(
+        &self,
+        buffer: NV_ENC_OUTPUT_PTR,
+    ) -> Result<(), NVENCSTATUS> {
+        let destroy_bitstream_buffer = entry_point!(self.lib, nvEncDestroyBitstreamBuffer);
+
+        unsafe { destroy_bitstream_buffer(self.inner, buffer) }.err()
+    }
+
+    /// Submits `input` for encoding into `output`.
+    ///
+    /// With HEVC alpha layer encoding `alpha` carries the alpha plane of YUV inputs, see
+    /// `LockedInput::upload_alpha`. ARGB and ABGR inputs provide their own alpha channel.
+    ///
+    /// With HEVC or AV1 temporal layers the pictures are assigned to layers in the order they
+    /// are submitted, see `EncodeConfig::enable_temporal_svc`.
+    ///
+    /// Returns `false` when the encoder needs more input before it can produce output, in which
+    /// case the pending output buffers are filled, in submission order, by a later call.
+    pub fn encode_picture(
+        &self,
+        input: &InputBuffer,
+        alpha: Option<&InputBuffer>,
+        output: &BitstreamBuffer,
+        timestamp: u64,
+    ) -> Result<bool, NVENCSTATUS> {
+        let mut params: NV_ENC_PIC_PARAMS = unsafe { mem::zeroed() };
+        params.version = NV_ENC_PIC_PARAMS_VER;
+        params.inputWidth = input.width;
+        params.inputHeight = input.height;
+        params.inputBuffer = input.inner;
+        params.alphaBuffer = alpha.map_or(std::ptr::null_mut(), |alpha| alpha.inner);
+        params.bufferFmt = input.format.into();
+        params.outputBitstream = output.inner;
+        params.pictureStruct = ffi::encode_api::_NV_ENC_PIC_STRUCT_NV_ENC_PIC_STRUCT_FRAME;
+        params.inputTimeStamp = timestamp;
+        if let Some((codec, layers)) = self.temporal_layers {
+            let picture = self.pictures.get();
+            self.pictures.set(picture + 1);
+            let temporal_id = temporal_id(picture, layers);
+            match codec {
+                Codec::HEVC => params.codecPicParams.hevcPicParams.temporalId = temporal_id,
+                _ => params.codecPicParams.av1PicParams.temporalId = temporal_id,
+            }
+        }
+
+        self.submit(&mut params)
+    }
+
+    /// Signals the end of the stream, so the encoder flushes every pending picture.
+    pub fn end_of_stream(&self) -> Result<(), NVENCSTATUS> {
+        let mut params: NV_ENC_PIC_PARAMS = unsafe { mem::zeroed() };
+        params.version = NV_ENC_PIC_PARAMS_VER;
+        params.encodePicFlags = ffi::encode_api::_NV_ENC_PIC_FLAGS_NV_ENC_PIC_FLAG_EOS as _;
+
+        self.submit(&mut params).map(|_| ())
+    }
+
+    fn submit(&self, params: &mut NV_ENC_PIC_PARAMS) -> Result<bool, NVENCSTATUS> {
+        let encode_picture = entry_point!(self.lib, nvEncEncodePicture);
+        let res = unsafe { encode_picture(self.inner, params) };
+        tracing::trace!("Encode picture = {}", res);
+
+        if res == ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_NEED_MORE_INPUT {
+            return Ok(false);
+        }
+
+        res.result(true)
+    }
+
+    /// Waits for `output` to be filled and copies the encoded picture out of it.
+    pub fn lock_bitstream(&self, output: &BitstreamBuffer) -> Result<Packet, NVENCSTATUS> {
+        let mut params: NV_ENC_LOCK_BITSTREAM = unsafe { mem::zeroed() };
+        params.version = NV_ENC_LOCK_BITSTREAM_VER;
+        params.outputBitstream = output.inner;
+
+        let lock_bitstream = entry_point!(self.lib, nvEncLockBitstream);
+        let res = unsafe { lock_bitstream(self.inner, &mut params) };
+        tracing::trace!(
+            "Lock bitstream = {}\t size = {}",
+            res,
+            params.bitstreamSizeInBytes
+        );
+        res.err()?;
+
+        let data = unsafe {
+            std::slice::from_raw_parts(
+                params.bitstreamBufferPtr as *const u8,
+                params.bitstreamSizeInBytes as usize,
+            )
+        };
+        let picture_type = params.pictureType;
+        let packet = Packet {
+            data: data.to_vec(),
+            timestamp: params.outputTimeStamp,
+            keyframe: picture_type == ffi::encode_api::_NV_ENC_PIC_TYPE_NV_ENC_PIC_TYPE_IDR
+                || picture_type == ffi::encode_api::_NV_ENC_PIC_TYPE_NV_ENC_PIC_TYPE_I,
+            alpha_layer_size: params.alphaLayerSizeInBytes as usize,
+            temporal_id: params.temporalId,
+        };
+
+        let unlock_bitstream = entry_point!(self.lib, nvEncUnlockBitstream);
+        unsafe { unlock_bitstream(self.inner, output.inner) }.result(packet)
+    }
+
     fn guids(&mut self) -> Result<Vec<NvGUID>, NVENCSTATUS> {
         unsafe {
             let mut guid_count: u32 = 0;
-            let get_guid_count = self
-                .lib
-                .api
-                .nvEncGetEncodeGUIDCount
-                .expect("nvEncGetEncodeGUIDCount not supported");
+            let get_guid_count = entry_point!(self.lib, nvEncGetEncodeGUIDCount);
 
             let res = get_guid_count(self.inner, &mut guid_count);
             tracing::trace!("Get GUID count = {}\t GUID count = {}", res, guid_count);
             res.err()?;
 
             let mut guids: Vec<NvGUID> = vec![mem::zeroed(); guid_count as usize];
-            let get_guids = self
-                .lib
-                .api
-                .nvEncGetEncodeGUIDs
-                .expect("nvEncGetEncodeGUIDs not supported");
+            let get_guids = entry_point!(self.lib, nvEncGetEncodeGUIDs);
 
             let res = get_guids(
                 self.inner,
@@ -156,38 +629,211 @@ impl<'a> Encoder<'a> {
     }
 }
 
-// impl Drop for Encoder<'_> {
-//     fn drop(&mut self) {
-//         unsafe {
-//             let destroy_encoder = self
-//                 .lib
-//                 .api
-//                 .nvEncDestroyEncoder
-//                 .expect("nvEncDestroyEncoder not supported");
-//                 tracing::trace!("Destroying encoder = {:p}", self.inner);
-//                 if !destroy_encoder(self.inner).ok() {
-//                 tracing::error!("Failed to destroy encoder.");
-//             }
-//         }
-//     }
-// }
+/// Layer of the `picture`th picture in a dyadic hierarchy of `layers` temporal layers.
+fn temporal_id(picture: u64, layers: u32) -> u32 {
+    let position = picture % (1 << (layers - 1));
+    if position == 0 {
+        return 0;
+    }
+
+    layers - 1 - position.trailing_zeros()
+}
+
+impl Drop for Encoder<'_> {
+    fn drop(&mut self) {
+        // nothing to destroy if opening the session failed
+        if self.inner.is_null() {
+            return;
+        }
+
+        if let Some(destroy_encoder) = self.lib.api.nvEncDestroyEncoder {
+            tracing::trace!("Destroying encoder = {:p}", self.inner);
+            if !unsafe { destroy_encoder(self.inner) }.ok() {
+                tracing::error!("Failed to destroy encoder.");
+            }
+        }
+    }
+}
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
+    use crate::cuda::test::{context, cuda};
     use crate::cuda::Cuda;
     use tracing_test::traced_test;
 
+    /// Runs `f` on an encoder in a context of its own on the first device.
+    pub(crate) fn with_encoder<T, F>(cuda: &Cuda, f: F) -> T
+    where
+        F: FnOnce(&mut Encoder) -> T,
+    {
+        let encode = Encode::new().unwrap();
+        let mut encoder = encode.new_encoder(context(cuda)).unwrap();
+
+        f(&mut encoder)
+    }
+
+    /// Fills the NV12 `input` with a flat picture of `luma` and neutral chroma.
+    pub(crate) fn upload_flat(encoder: &Encoder, input: &InputBuffer, luma: u8) {
+        let (width, height) = (input.width() as usize, input.height() as usize);
+        let mut locked = encoder.lock_input(input).unwrap();
+        locked
+            .upload_nv12(
+                &vec![luma; width * height],
+                width,
+                &vec![128u8; width * height / 2],
+                width,
+            )
+            .unwrap();
+        encoder.unlock_input(locked).unwrap();
+    }
+
     #[test]
     #[traced_test]
     fn encoder_create() {
         // let api = API::new().unwrap();
-        let cuda = Cuda::new().unwrap();
-        cuda.init(0).unwrap();
-        let device = cuda.new_device(0).unwrap();
-        let ctx = cuda.new_context(device, 0).unwrap();
+        with_encoder(&cuda(), |encoder| {
+            let guids = encoder.guids().unwrap();
+        });
+    }
+
+    #[test]
+    #[traced_test]
+    fn encoder_input_buffer_pool() {
+        with_encoder(&cuda(), |encoder| {
+            let mut params: NV_ENC_INITIALIZE_PARAMS = unsafe { mem::zeroed() };
+            params.encodeGUID = ffi::constants::encode_api::NV_ENC_CODEC_H264_GUID;
+            params.presetGUID = ffi::constants::encode_api::NV_ENC_PRESET_P4_GUID;
+            params.tuningInfo = ffi::encode_api::NV_ENC_TUNING_INFO_NV_ENC_TUNING_INFO_HIGH_QUALITY;
+            params.encodeWidth = 64;
+            params.encodeHeight = 64;
+            params.frameRateNum = 30;
+            params.frameRateDen = 1;
+            params.enablePTD = 1;
+            encoder.initialize(&mut params).unwrap();
+
+            let pool = encoder
+                .input_buffer_pool(64, 64, BufferFormat::NV12, 2)
+                .unwrap();
+            let input = pool.acquire().unwrap();
+            assert_eq!(pool.available(), 1);
+
+            upload_flat(encoder, &input, 16);
+
+            drop(input);
+            assert_eq!(pool.available(), 2);
+        });
+    }
+
+    #[test]
+    #[traced_test]
+    fn encoder_alpha_layer() {
+        with_encoder(&cuda(), |encoder| {
+            let mut config = encoder
+                .preset_config(
+                    Codec::HEVC,
+                    ffi::constants::encode_api::NV_ENC_PRESET_P4_GUID,
+                    ffi::encode_api::NV_ENC_TUNING_INFO_NV_ENC_TUNING_INFO_HIGH_QUALITY,
+                )
+                .unwrap();
+            config.as_raw_mut().frameIntervalP = 1;
+            config.enable_alpha_layer(3).unwrap();
+            assert!(config.alpha_layer());
+            encoder
+                .initialize_with_config(&mut config, 64, 64, (30, 1))
+                .unwrap();
+
+            let pool = encoder
+                .input_buffer_pool(64, 64, BufferFormat::NV12, 2)
+                .unwrap();
+            let input = pool.acquire().unwrap();
+            let alpha = pool.acquire().unwrap();
+            upload_flat(encoder, &input, 16);
+            {
+                let mut locked = encoder.lock_input(&alpha).unwrap();
+                locked.upload_alpha(&vec![255u8; 64 * 64], 64).unwrap();
+                encoder.unlock_input(locked).unwrap();
+            }
+
+            let output = encoder.bitstream_buffer().unwrap();
+            assert!(encoder
+                .encode_picture(&input, Some(&alpha), &output, 0)
+                .unwrap());
+            let packet = encoder.lock_bitstream(&output).unwrap();
+            assert!(packet.keyframe);
+            assert!(packet.alpha_layer_size > 0);
+            assert!(packet.alpha_layer_size < packet.data.len());
+            encoder.end_of_stream().unwrap();
+        });
+    }
+
+    /// Temporal layers of 8 pictures encoded with 3 temporal layers.
+    fn temporal_ids(codec: Codec) -> Vec<u32> {
+        with_encoder(&cuda(), |encoder| {
+            let mut config = encoder
+                .preset_config(
+                    codec,
+                    ffi::constants::encode_api::NV_ENC_PRESET_P4_GUID,
+                    ffi::encode_api::NV_ENC_TUNING_INFO_NV_ENC_TUNING_INFO_LOW_LATENCY,
+                )
+                .unwrap();
+            config.as_raw_mut().frameIntervalP = 1;
+            config.enable_temporal_svc(3).unwrap();
+            assert_eq!(config.temporal_layers(), 3);
+            encoder
+                .initialize_with_config(&mut config, 64, 64, (30, 1))
+                .unwrap();
+
+            let pool = encoder
+                .input_buffer_pool(64, 64, BufferFormat::NV12, 1)
+                .unwrap();
+            let input = pool.acquire().unwrap();
+            upload_flat(encoder, &input, 16);
+
+            let output = encoder.bitstream_buffer().unwrap();
+            let layers = (0..8)
+                .map(|timestamp| {
+                    assert!(encoder
+                        .encode_picture(&input, None, &output, timestamp)
+                        .unwrap());
+                    encoder.lock_bitstream(&output).unwrap().temporal_id
+                })
+                .collect();
+            encoder.end_of_stream().unwrap();
+
+            layers
+        })
+    }
+
+    #[test]
+    #[traced_test]
+    fn encoder_temporal_svc() {
+        let layers = temporal_ids(Codec::H264);
+        assert_eq!(layers[0], 0);
+        assert!(layers.iter().all(|&id| id < 3));
+        assert!(layers.iter().any(|&id| id > 0));
+    }
+
+    #[test]
+    #[traced_test]
+    fn encoder_temporal_svc_hevc() {
+        assert_eq!(temporal_ids(Codec::HEVC), vec![0, 2, 1, 2, 0, 2, 1, 2]);
+    }
+
+    #[test]
+    fn dyadic_temporal_ids() {
+        let ids = (0..8)
+            .map(|picture| temporal_id(picture, 3))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 2, 1, 2, 0, 2, 1, 2]);
+        assert!((0..4).all(|picture| temporal_id(picture, 1) == 0));
+    }
+
+    #[test]
+    #[traced_test]
+    fn encoder_max_supported_version() {
         let encode = Encode::new().unwrap();
-        let mut encoder = encode.new_encoder(ctx).unwrap();
-        let guids = encoder.guids().unwrap();
+        let version = encode.max_supported_version().unwrap();
+        assert!(version >= (NVENCAPI_MAJOR_VERSION, NVENCAPI_MINOR_VERSION));
     }
 }
@@ -0,0 +1,382 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use ffi::cuda::CUresult;
+use ffi::encode_api::NVENCSTATUS;
+
+use crate::cuda::device::CuDevice;
+use crate::cuda::{Cuda, CudaResult};
+
+use super::{Encode, Encoder};
+
+/// Errors raised while opening a pooled encode session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    /// Getting the device or retaining its primary CUDA context failed.
+    Cuda(CUresult),
+    /// The driver refused to open the session for a reason other than the session limit.
+    Encode(NVENCSTATUS),
+    /// The pool has no devices to open sessions on.
+    NoDevice,
+    /// The device ordinal is not one of the pool's devices.
+    UnknownDevice(i32),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionError::Cuda(res) => {
+                write!(f, "CUDA error {} setting up the primary context", res)
+            }
+            SessionError::Encode(res) => write!(f, "nvEncOpenEncodeSessionEx = {}", res),
+            SessionError::NoDevice => write!(f, "no device to open an encode session on"),
+            SessionError::UnknownDevice(ordinal) => write!(f, "device {} is not pooled", ordinal),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+struct DeviceSessions {
+    ordinal: i32,
+    active: usize,
+    // either configured or learned from the driver refusing a session
+    limit: Option<usize>,
+    learned: bool,
+    // whether the pool holds a reference on the device's primary context
+    retained: bool,
+}
+
+impl DeviceSessions {
+    fn available(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.active < limit,
+            None => true,
+        }
+    }
+}
+
+struct State {
+    devices: Vec<DeviceSessions>,
+    // device that wins ties next, so equally loaded GPUs take turns
+    next: usize,
+}
+
+/// Opens encode sessions across several GPUs without exceeding the per-device session limit.
+///
+/// Each new session goes to the least loaded device. When every device is at its limit
+/// `acquire` waits until a `PooledEncoder` is dropped instead of failing. Limits can be set
+/// with `set_session_limit`, otherwise they are learned when the driver refuses a session with
+/// `NV_ENC_ERR_OUT_OF_MEMORY` or `NV_ENC_ERR_INCOMPATIBLE_CLIENT_KEY`. A learned limit only
+/// holds until the next session on the device is released, as sessions of other processes may
+/// have been closed meanwhile, then the driver is asked again.
+///
+/// The sessions of a device share its primary context, which the pool keeps alive until it
+/// is dropped.
+pub struct SessionPool<'a> {
+    encode: &'a Encode,
+    cuda: &'a Cuda,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+impl<'a> SessionPool<'a> {
+    /// Creates a pool over every device visible to `cuda`.
+    pub fn new(encode: &'a Encode, cuda: &'a Cuda) -> Result<Self, CUresult> {
+        let ordinals = (0..cuda.device_count()?).collect::<Vec<_>>();
+
+        Ok(Self::with_devices(encode, cuda, &ordinals))
+    }
+
+    /// Creates a pool over the devices with the given ordinals.
+    pub fn with_devices(encode: &'a Encode, cuda: &'a Cuda, ordinals: &[i32]) -> Self {
+        let devices = ordinals
+            .iter()
+            .map(|&ordinal| DeviceSessions {
+                ordinal,
+                active: 0,
+                limit: None,
+                learned: false,
+                retained: false,
+            })
+            .collect();
+
+        Self {
+            encode,
+            cuda,
+            state: Mutex::new(State { devices, next: 0 }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Caps the number of sessions opened on the device `ordinal`, `None` removes the cap.
+    ///
+    /// Fails with `SessionError::UnknownDevice` if the pool was not created over the device.
+    pub fn set_session_limit(
+        &self,
+        ordinal: i32,
+        limit: Option<usize>,
+    ) -> Result<(), SessionError> {
+        let mut state = self.lock();
+        let device = state
+            .devices
+            .iter_mut()
+            .find(|d| d.ordinal == ordinal)
+            .ok_or(SessionError::UnknownDevice(ordinal))?;
+        device.limit = limit;
+        device.learned = false;
+        self.released.notify_all();
+
+        Ok(())
+    }
+
+    /// Number of sessions currently open through the pool on the device `ordinal`.
+    pub fn active_sessions(&self, ordinal: i32) -> usize {
+        self.lock()
+            .devices
+            .iter()
+            .find(|d| d.ordinal == ordinal)
+            .map_or(0, |d| d.active)
+    }
+
+    /// Opens a session on the least loaded device, waiting for one to be released if all are busy.
+    pub fn acquire(&self) -> Result<PooledEncoder<'_, 'a>, SessionError> {
+        let mut state = self.lock();
+        loop {
+            match self.reserve(&mut state) {
+                Some(index) => {
+                    drop(state);
+                    if let Some(encoder) = self.open(index)? {
+                        return Ok(encoder);
+                    }
+                    state = self.lock();
+                }
+                None if state.devices.is_empty() => return Err(SessionError::NoDevice),
+                None => {
+                    tracing::debug!("All devices are at their session limit, waiting");
+                    state = self.released.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+        }
+    }
+
+    /// Opens a session like `acquire`, but returns `None` instead of waiting.
+    pub fn try_acquire(&self) -> Result<Option<PooledEncoder<'_, 'a>>, SessionError> {
+        loop {
+            let index = {
+                let mut state = self.lock();
+                match self.reserve(&mut state) {
+                    Some(index) => index,
+                    None if state.devices.is_empty() => return Err(SessionError::NoDevice),
+                    None => return Ok(None),
+                }
+            };
+            if let Some(encoder) = self.open(index)? {
+                return Ok(Some(encoder));
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // the state stays consistent even if a holder panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Picks the least loaded device with a free slot and counts the session against it.
+    fn reserve(&self, state: &mut State) -> Option<usize> {
+        let count = state.devices.len();
+        let start = state.next;
+        let index = (0..count)
+            .map(|i| (start + i) % count)
+            .filter(|&i| state.devices[i].available())
+            .min_by_key(|&i| state.devices[i].active)?;
+
+        state.devices[index].active += 1;
+        state.next = (index + 1) % count;
+
+        Some(index)
+    }
+
+    /// Opens the session reserved on `index`, `None` means the device turned out to be full.
+    fn open(&self, index: usize) -> Result<Option<PooledEncoder<'_, 'a>>, SessionError> {
+        let ordinal = self.lock().devices[index].ordinal;
+
+        let encoder = self
+            .cuda
+            .new_device(ordinal)
+            .and_then(|device| {
+                let ctx = self.cuda.primary_context(device)?;
+                self.retain(index, device)?;
+
+                Ok(ctx)
+            })
+            .map_err(SessionError::Cuda)
+            .and_then(|ctx| self.encode.new_encoder(ctx).map_err(SessionError::Encode));
+
+        match encoder {
+            Ok(encoder) => {
+                tracing::trace!("Opened encode session on device {}", ordinal);
+                Ok(Some(PooledEncoder {
+                    pool: self,
+                    index,
+                    encoder: Some(encoder),
+                }))
+            }
+            Err(SessionError::Encode(res)) if is_session_limit(res) => {
+                let mut state = self.lock();
+                let device = &mut state.devices[index];
+                device.active -= 1;
+                tracing::debug!(
+                    "Device {} refused a session with {} already open",
+                    ordinal,
+                    device.active
+                );
+                // sessions held by other processes are not ours to wait for
+                if device.active == 0 {
+                    return Err(SessionError::Encode(res));
+                }
+                device.limit = Some(device.active);
+                device.learned = true;
+
+                Ok(None)
+            }
+            Err(err) => {
+                self.release(index);
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Keeps the primary context of `device` alive for the pool, so that it is not destroyed
+    /// and recreated whenever the device runs out of sessions.
+    fn retain(&self, index: usize, device: CuDevice) -> Result<(), CUresult> {
+        let mut state = self.lock();
+        if !state.devices[index].retained {
+            let mut ctx = std::ptr::null_mut();
+            unsafe { self.cuda.cuDevicePrimaryCtxRetain(&mut ctx, device.inner) }.err()?;
+            state.devices[index].retained = true;
+        }
+
+        Ok(())
+    }
+
+    fn release(&self, index: usize) {
+        let mut state = self.lock();
+        let device = &mut state.devices[index];
+        device.active -= 1;
+        if device.learned {
+            device.limit = None;
+            device.learned = false;
+        }
+        self.released.notify_one();
+    }
+}
+
+impl Drop for SessionPool<'_> {
+    fn drop(&mut self) {
+        for device in self.lock().devices.iter().filter(|d| d.retained) {
+            let res = self
+                .cuda
+                .new_device(device.ordinal)
+                .and_then(|d| unsafe { self.cuda.cuDevicePrimaryCtxRelease_v2(d.inner) }.err());
+            if res.is_err() {
+                tracing::error!("Failed to release primary cuda context.");
+            }
+        }
+    }
+}
+
+fn is_session_limit(res: NVENCSTATUS) -> bool {
+    res == ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_OUT_OF_MEMORY
+        || res == ffi::encode_api::_NVENCSTATUS_NV_ENC_ERR_INCOMPATIBLE_CLIENT_KEY
+}
+
+/// An `Encoder` handed out by a `SessionPool`, its slot is given back on drop.
+pub struct PooledEncoder<'p, 'a: 'p> {
+    pool: &'p SessionPool<'a>,
+    index: usize,
+    encoder: Option<Encoder<'a>>,
+}
+
+impl PooledEncoder<'_, '_> {
+    /// Ordinal of the device the session was opened on.
+    pub fn device_ordinal(&self) -> i32 {
+        self.pool.lock().devices[self.index].ordinal
+    }
+}
+
+impl<'a> Deref for PooledEncoder<'_, 'a> {
+    type Target = Encoder<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        self.encoder.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledEncoder<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.encoder.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledEncoder<'_, '_> {
+    fn drop(&mut self) {
+        // close the session before waking a waiter that would open a new one
+        drop(self.encoder.take());
+        self.pool.release(self.index);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cuda::test::cuda;
+    use crate::encode::Encode;
+    use tracing_test::traced_test;
+
+    use super::{SessionError, SessionPool};
+
+    #[test]
+    #[traced_test]
+    fn session_pool_limit() {
+        let cuda = cuda();
+        let encode = Encode::new().unwrap();
+        let pool = SessionPool::with_devices(&encode, &cuda, &[0]);
+        pool.set_session_limit(0, Some(1)).unwrap();
+        assert_eq!(
+            pool.set_session_limit(1, Some(1)),
+            Err(SessionError::UnknownDevice(1))
+        );
+
+        let encoder = pool.try_acquire().unwrap().unwrap();
+        assert_eq!(encoder.device_ordinal(), 0);
+        assert_eq!(pool.active_sessions(0), 1);
+        assert!(pool.try_acquire().unwrap().is_none());
+
+        drop(encoder);
+        assert_eq!(pool.active_sessions(0), 0);
+        assert!(pool.try_acquire().unwrap().is_some());
+    }
+
+    #[test]
+    #[traced_test]
+    fn session_pool_waits_for_release() {
+        let cuda = cuda();
+        let encode = Encode::new().unwrap();
+        let pool = SessionPool::new(&encode, &cuda).unwrap();
+        for ordinal in 0..cuda.device_count().unwrap() {
+            pool.set_session_limit(ordinal, Some(1)).unwrap();
+        }
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let _encoder = pool.acquire().unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                });
+            }
+        });
+        assert_eq!(pool.active_sessions(0), 0);
+    }
+}
@@ -31,10 +31,11 @@
 //! -- See (Nvidia Encoder Programming Guide)[https://docs.nvidia.com/video-technologies/video-codec-sdk/nvenc-video-encoder-api-prog-guide/] for more info
 
 extern crate nvidia_video_codec_sys as ffi;
+extern crate futures_core;
 #[cfg(test)]
 extern crate tracing_test;
 
 pub mod cuda;
-// pub mod cuvid;
+pub mod cuvid;
 // pub mod npp;
 pub mod encode;
\ No newline at end of file